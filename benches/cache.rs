@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use fasthash::farm::Hash64 as HasherFn;
+
+use no_dns::dns::cache::{Entry, Key, PrefetchPolicy, Shards, TtlPolicy, SHARD_COUNT};
+use no_dns::dns::dto::datagram::Datagram;
+use no_dns::dns::dto::enums::{CLASS, TYPE};
+use no_dns::dns::dto::header::{Header, RCODE};
+use no_dns::dns::dto::name::Name;
+use no_dns::dns::dto::question::Question;
+use no_dns::dns::dto::rdata::RData;
+use no_dns::dns::dto::resource_record::ResourceRecord;
+
+const THREADS: usize = 8;
+const QUESTIONS: usize = 1000;
+
+/// # Baseline
+///
+/// The cache before it was sharded: a single map behind a lock, whose
+/// expired entries are removed by the reader that finds them, under the
+/// write lock.
+struct Baseline {
+    map: RwLock<HashMap<Key, (Entry, Instant), HasherFn>>,
+}
+
+impl Baseline {
+    fn new() -> Self {
+        Self {
+            map: RwLock::new(HashMap::with_capacity_and_hasher(16, HasherFn)),
+        }
+    }
+
+    fn get(&self, key: &Key) -> Option<Entry> {
+        let map_reader = self.map.read().expect("Cache lock poisoned");
+        let (mut entry, inserted) = map_reader.get(key).cloned()?;
+        drop(map_reader);
+        let elapsed_time = inserted.elapsed().as_secs() as u32;
+        if elapsed_time >= entry.get_ttl() {
+            self.map.write().expect("Cache lock poisoned").remove(key);
+            return None;
+        }
+        entry.age(elapsed_time);
+        Some(entry)
+    }
+
+    fn insert(&self, key: &Key, entry: Entry) {
+        let mut map_writer = self.map.write().expect("Cache lock poisoned");
+        map_writer.insert(key.clone(), (entry, Instant::now()));
+    }
+}
+
+/// Cache under benchmark.
+trait Cache: Send + Sync {
+    fn get(&self, key: &Key) -> Option<Entry>;
+    fn insert(&self, key: &Key, entry: Entry);
+}
+
+impl Cache for Baseline {
+    fn get(&self, key: &Key) -> Option<Entry> {
+        Baseline::get(self, key)
+    }
+
+    fn insert(&self, key: &Key, entry: Entry) {
+        Baseline::insert(self, key, entry)
+    }
+}
+
+impl Cache for Shards {
+    fn get(&self, key: &Key) -> Option<Entry> {
+        Shards::get(self, key)
+    }
+
+    fn insert(&self, key: &Key, entry: Entry) {
+        Shards::insert(self, key, entry)
+    }
+}
+
+fn key(index: usize) -> Key {
+    let question = Question {
+        qname: Name::from(format!("{}.example.com", index).as_str()),
+        content: [0, 1, 0, 1],
+    };
+    Key::new(&question, false, false)
+}
+
+fn entry(ttl: u32) -> Entry {
+    let answer = ResourceRecord::new(Name::from("example.com"), TYPE::A, CLASS::IN, ttl, RData::A(Ipv4Addr::new(93, 184, 216, 34)));
+    let mut reply = Datagram {
+        header: Header::new_reply(),
+        questions: vec![],
+        answers: vec![answer],
+        authorities: vec![],
+        additionals: vec![],
+    };
+    reply.header.set_rcode(RCODE::NoError);
+    reply.update_counts();
+    let policy = TtlPolicy {
+        min_ttl: 0,
+        max_ttl: u32::MAX,
+        max_negative_ttl: u32::MAX,
+        cache_zero_ttl: false,
+    };
+    Entry::from_reply(&reply, &policy).unwrap()
+}
+
+/// Runs `iterations` operations on each of `THREADS` threads at once, one
+/// write for every 10 reads like a cache with a 90% hit rate, and returns
+/// the time they took.
+fn run(cache: &Arc<dyn Cache>, keys: &Arc<Vec<Key>>, entry: &Entry, iterations: u64) -> Duration {
+    let start = Instant::now();
+    let threads: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let cache = Arc::clone(cache);
+            let keys = Arc::clone(keys);
+            let entry = entry.clone();
+            thread::spawn(move || {
+                for i in 0..iterations as usize {
+                    let key = &keys[(i * 7 + thread) % QUESTIONS];
+                    if i % 10 == 0 {
+                        cache.insert(key, entry.clone());
+                    } else {
+                        criterion::black_box(cache.get(key));
+                    }
+                }
+            })
+        })
+        .collect();
+    threads.into_iter().for_each(|thread| thread.join().unwrap());
+    start.elapsed()
+}
+
+/// Compares concurrent reads and writes on the baseline cache and on the
+/// sharded cache, with a single shard and with the default number of shards.
+///
+/// With a TTL of 1 second, entries keep expiring while they are read, which
+/// takes the write lock of the baseline cache.
+fn bench_cache(c: &mut Criterion) {
+    let keys: Arc<Vec<Key>> = Arc::new((0..QUESTIONS).map(key).collect());
+    for ttl in [300, 1] {
+        let mut group = c.benchmark_group(format!("cache_ttl_{}", ttl));
+        let entry = entry(ttl);
+        let caches: Vec<(String, Arc<dyn Cache>)> = vec![
+            (String::from("baseline"), Arc::new(Baseline::new())),
+            (String::from("1_shard"), Arc::new(Shards::new(1, 0, 0, 0, no_prefetch()))),
+            (format!("{}_shards", SHARD_COUNT), Arc::new(Shards::new(SHARD_COUNT, 0, 0, 0, no_prefetch()))),
+        ];
+        for (name, cache) in caches {
+            for key in keys.iter() {
+                cache.insert(key, entry.clone());
+            }
+            group.bench_function(BenchmarkId::from_parameter(name), |b| {
+                b.iter_custom(|iterations| run(&cache, &keys, &entry, iterations))
+            });
+        }
+        group.finish();
+    }
+}
+
+fn no_prefetch() -> PrefetchPolicy {
+    PrefetchPolicy { threshold: 0, min_hits: 0 }
+}
+
+criterion_group!(benches, bench_cache);
+criterion_main!(benches);
@@ -14,8 +14,10 @@ pub fn get_elements() -> Vec<Name> {
         Ok(lines) => {
             for line in lines {
                 if let Ok(content) = line {
+                    // Names may be written fully qualified, with a final dot.
+                    let content = content.trim().trim_end_matches('.');
                     if content.len() > 0 {
-                        list.push(Name::from(content.as_str()));
+                        list.push(Name::from(content));
                     }
                 }
             }
@@ -5,6 +5,7 @@ use fasthash::city as Hasher;
 use lazy_static::lazy_static;
 
 use crate::dns::dto::label::Label;
+use crate::dns::dto::name::Name;
 
 lazy_static! {
     static ref BLOCKLIST: Blocklist = Blocklist::init();
@@ -18,19 +19,29 @@ impl Blocklist {
     const ROOT_ELEMENT: usize = 0;
 
     fn init() -> Self {
+        Self::from_names(file::get_elements())
+    }
+
+    fn from_names(names: Vec<Name>) -> Self {
         let mut bl = Self::new();
 
-        file::get_elements().into_iter().for_each(|n| {
+        names.into_iter().for_each(|n| {
             let mut labels = n.labels.as_slices().0.iter().cloned().collect::<Vec<Label>>();
             let mut wildcard = false;
             if let Some(l) = labels.get(0) {
-                if l.as_str() == "*" {
+                if l.as_bytes() == b"*" {
                     wildcard = true;
                 }
             }
             if wildcard {
                 labels.remove(0);
             }
+            // Labels are matched one by one, so an entry with a label holding
+            // spaces or other characters could never match a query.
+            if labels.is_empty() || !labels.iter().all(Label::is_valid) {
+                log::warn!("Ignoring invalid blocklist entry {}", n);
+                return;
+            }
             bl.add_element(labels, wildcard)
         });
 
@@ -49,8 +60,8 @@ impl Blocklist {
         let mut element = Self::ROOT_ELEMENT;
         while name.len() > 0 {
             let label = name.pop().unwrap();
-            log::debug!("Inserting {} in the blocklist, wildcard={}, blocked={}", label.as_str(), wildcard && name.len() == 0, name.len() == 0);
-            match self.labels[element].children.get(label.as_str()) {
+            log::debug!("Inserting {} in the blocklist, wildcard={}, blocked={}", label, wildcard && name.len() == 0, name.len() == 0);
+            match self.labels[element].children.get(&label) {
                 Some(c) => {
                     element = *c;
                     if name.len() == 0 {
@@ -60,7 +71,7 @@ impl Blocklist {
                 None => {
                     let element_count = self.labels.len();
                     self.labels.push(Node::new(name.len() == 0, name.len() == 0 && wildcard));
-                    self.labels[element].children.insert(label.value.clone(), element_count);
+                    self.labels[element].children.insert(label, element_count);
                     element = element_count;
                 }
             }
@@ -72,35 +83,46 @@ impl Blocklist {
 
 }
 
-pub fn contains(mut name: &[Label]) -> bool {
-    let mut element = Blocklist::ROOT_ELEMENT;
-    while !BLOCKLIST.labels[element].is_leaf() {
-        log::debug!("Checking if blocklist contains {}", name[name.len() - 1].as_str());
-        log::debug!("Exploring element with wildcard={}", BLOCKLIST.labels[element].wildcard);
-        if BLOCKLIST.labels[element].wildcard {
-            return true;
+pub fn contains(name: &[Label]) -> bool {
+    BLOCKLIST.contains(name)
+}
+
+impl Blocklist {
+    /// Labels are matched without regard to case. The root name, without
+    /// labels, is never blocked.
+    fn contains(&self, mut name: &[Label]) -> bool {
+        if name.is_empty() {
+            return false;
         }
-        match BLOCKLIST.labels[element].children.get(name[name.len() - 1].as_str()) {
-            Some(c) => {
-                // TODO: Add better formulation
-                element = *c;
-                if name.len() == 1 {
-                    return BLOCKLIST.labels[element].blocked;
-                } else {
-                    name = &name[0..name.len() - 1];
+        let mut element = Blocklist::ROOT_ELEMENT;
+        while !self.labels[element].is_leaf() {
+            log::debug!("Checking if blocklist contains {}", name[name.len() - 1]);
+            log::debug!("Exploring element with wildcard={}", self.labels[element].wildcard);
+            if self.labels[element].wildcard {
+                return true;
+            }
+            match self.labels[element].children.get(&name[name.len() - 1]) {
+                Some(c) => {
+                    // TODO: Add better formulation
+                    element = *c;
+                    if name.len() == 1 {
+                        return self.labels[element].blocked;
+                    } else {
+                        name = &name[0..name.len() - 1];
+                    }
+                },
+                None => {
+                    return false;
                 }
-            },
-            None => {
-                return false;
             }
         }
-    }
 
-    BLOCKLIST.labels[element].wildcard
+        self.labels[element].wildcard
+    }
 }
 
 struct Node {
-    children: HashMap<String, usize, Hasher::Hash64>,
+    children: HashMap<Label, usize, Hasher::Hash64>,
     wildcard: bool,
     blocked: bool,
 }
@@ -119,3 +141,48 @@ impl Node {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(name: &str) -> Vec<Label> {
+        Name::from(name).labels.into_iter().collect()
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let mut bl = Blocklist::new();
+        bl.add_element(labels("Example.com"), false);
+        bl.add_element(labels("google.COM"), true);
+
+        assert!(bl.contains(&labels("example.com")));
+        assert!(bl.contains(&labels("EXAMPLE.com")));
+        assert!(bl.contains(&labels("www.Google.com")));
+        assert!(!bl.contains(&labels("example.org")));
+    }
+
+    #[test]
+    fn test_root_name() {
+        let mut bl = Blocklist::new();
+        bl.add_element(labels("example.com"), true);
+
+        assert!(!bl.contains(&labels(".")));
+        assert!(!bl.contains(&[]));
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        let bl = Blocklist::from_names(vec![
+            Name::from("youtube.com"),
+            Name::from("*.test.ca"),
+            Name::from("google com"),
+            Name::from("*"),
+        ]);
+
+        assert!(bl.contains(&labels("youtube.com")));
+        assert!(bl.contains(&labels("www.test.ca")));
+        assert!(!bl.contains(&labels("www.google.com")));
+        assert!(!bl.contains(&labels("example.org")));
+    }
+}
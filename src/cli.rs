@@ -1,6 +1,19 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::Parser;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use hyper::Uri;
+
+use crate::dns::dto::opt::Opt;
+use crate::dns::server::https::DOH_PORT;
+use crate::dns::server::tls::DOT_PORT;
+
+/// Time to wait for the system resolver to resolve a DNS-over-HTTPS server.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 #[command(name = "No DNS")]
@@ -16,20 +29,134 @@ pub struct Args {
     #[arg(short, long, default_value_t = String::from("0.0.0.0:53"))]
     pub bind: String,
 
-    /// Upstream DNS server IP
-    #[arg(short, long, default_value_t = String::from("8.8.8.8"))]
-    pub upstream: String,
+    /// Upstream DNS servers, as IP[:port], tls://IP[:port][#name][#pin]... for DNS-over-TLS
+    /// or https://host[:port]/path[#IP][#pin]... for DNS-over-HTTPS, separated by commas.
+    /// Pins are base64 SHA-256 digests of the public keys the server may have
+    #[arg(short, long, value_delimiter = ',', default_value = "8.8.8.8")]
+    pub upstream: Vec<String>,
 
-    /// Upstream DNS server port
+    /// Upstream DNS server port, for servers given without one
     #[arg(long, default_value_t = 53)]
     pub upstream_port: u16,
+
+    /// How to choose between upstream servers
+    #[arg(long, value_enum, default_value_t = UpstreamStrategy::Fastest)]
+    pub upstream_strategy: UpstreamStrategy,
+
+    /// Milliseconds to wait for each upstream server before failing over to the next one
+    #[arg(long, default_value_t = 2000)]
+    pub upstream_timeout: u64,
+
+    /// Times every upstream server is tried again when none replied
+    #[arg(long, default_value_t = 2)]
+    pub upstream_retries: u32,
+
+    /// Milliseconds to wait before the first retry, doubled for each of the next ones
+    #[arg(long, default_value_t = 100)]
+    pub upstream_backoff: u64,
+
+    /// Milliseconds after which a client whose query upstream servers did not
+    /// answer yet gets a stale reply or a server failure, while they are still tried
+    #[arg(long, default_value_t = 1800)]
+    pub client_timeout: u64,
+
+    /// UDP payload size advertised with EDNS(0)
+    #[arg(long, default_value_t = 1232)]
+    pub edns_payload_size: u16,
+
+    /// Maximum number of cached replies, 0 for no limit
+    #[arg(long, default_value_t = 10_000)]
+    pub cache_max_entries: usize,
+
+    /// Maximum size of cached replies in bytes, 0 for no limit
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    pub cache_max_bytes: usize,
+
+    /// Interval in seconds between removals of expired cache entries
+    #[arg(long, default_value_t = 60)]
+    pub cache_sweep_interval: u64,
+
+    /// Minimum TTL in seconds of cached replies
+    #[arg(long, default_value_t = 0)]
+    pub cache_min_ttl: u32,
+
+    /// Maximum TTL in seconds of cached replies
+    #[arg(long, default_value_t = 86_400)]
+    pub cache_max_ttl: u32,
+
+    /// Maximum TTL in seconds of cached negative replies (NXDOMAIN and NODATA)
+    #[arg(long, default_value_t = 3_600)]
+    pub cache_max_negative_ttl: u32,
+
+    /// Cache replies with a TTL of 0 for the minimum TTL instead of only
+    /// forwarding them
+    #[arg(long, default_value_t = false)]
+    pub cache_zero_ttl: bool,
+
+    /// Time in seconds during which expired replies are kept, to be served
+    /// when the upstream server cannot be reached
+    #[arg(long, default_value_t = 86_400)]
+    pub cache_stale_window: u32,
+
+    /// Refresh popular cache entries read within this last percentage of
+    /// their TTL, 0 to disable
+    #[arg(long, default_value_t = 10)]
+    pub prefetch_threshold: u32,
+
+    /// Number of reads after which a cache entry is refreshed before it expires
+    #[arg(long, default_value_t = 3)]
+    pub prefetch_min_hits: u32,
+
+    /// File where the cache is saved on shutdown and periodically, and
+    /// loaded from at startup
+    #[arg(long)]
+    pub cache_file: Option<PathBuf>,
+
+    /// Interval in seconds between saves of the cache to the cache file
+    #[arg(long, default_value_t = 300)]
+    pub cache_save_interval: u64,
+
+    /// How to answer a query with several questions when some are blocked
+    #[arg(long, value_enum, default_value_t = BlockPolicy::Message)]
+    pub block_policy: BlockPolicy,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum BlockPolicy {
+    /// The whole message is answered as non-existent.
+    Message,
+
+    /// Only the blocked questions are left unanswered.
+    Question,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum UpstreamStrategy {
+    /// Servers are tried in the order they were given.
+    Ordered,
+
+    /// Each query starts from the server after the one of the previous query.
+    RoundRobin,
+
+    /// Servers are tried from the one with the lowest latency.
+    Fastest,
+
+    /// The query is sent to every server, keeping the first reply.
+    Race,
 }
 
 impl Args {
+    #[cfg(not(test))]
     pub fn get_params() -> Args {
         return Args::parse();
     }
 
+    /// The arguments of the test harness are not meant for the application.
+    #[cfg(test)]
+    pub fn get_params() -> Args {
+        Args::parse_from(["no-dns"])
+    }
+
     pub fn get_bind(&self) -> SocketAddr {
         return self
             .bind
@@ -37,9 +164,124 @@ impl Args {
             .expect("Unable to parse the bind socket address");
     }
 
-    pub fn get_upstream(&self) -> SocketAddr {
-        return format!("{}:{}", self.upstream, self.upstream_port)
-            .parse()
-            .expect("Unable to parse the upstream socket address");
+    /// Largest UDP datagram accepted from clients and upstream servers.
+    pub fn get_edns_payload_size(&self) -> u16 {
+        self.edns_payload_size.max(Opt::MIN_PAYLOAD_SIZE)
+    }
+
+    pub fn get_cache_sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.cache_sweep_interval.max(1))
+    }
+
+    pub fn get_cache_save_interval(&self) -> Duration {
+        Duration::from_secs(self.cache_save_interval.max(1))
+    }
+
+    pub fn get_upstream_timeout(&self) -> Duration {
+        Duration::from_millis(self.upstream_timeout.max(1))
+    }
+
+    pub fn get_client_timeout(&self) -> Duration {
+        Duration::from_millis(self.client_timeout.max(1))
+    }
+
+    pub fn get_upstream_backoff(&self) -> Duration {
+        Duration::from_millis(self.upstream_backoff)
+    }
+
+    /// Reads the upstream servers, or the first invalid one.
+    pub async fn get_upstreams(&self) -> Result<Vec<UpstreamAddress>, String> {
+        let mut upstreams = Vec::with_capacity(self.upstream.len());
+        for upstream in self.upstream.iter() {
+            upstreams.push(self.parse_upstream(upstream).await?);
+        }
+        Ok(upstreams)
+    }
+
+    async fn parse_upstream(&self, upstream: &str) -> Result<UpstreamAddress, String> {
+        if let Some(upstream) = upstream.strip_prefix("tls://") {
+            let mut parts = upstream.split('#');
+            let address = parse_socket_address(parts.next().unwrap_or_default(), DOT_PORT)?;
+            let name = parts.next().unwrap_or_default();
+            // Without a name, the certificate must be valid for the IP address.
+            let name = if name.is_empty() { address.ip().to_string() } else { name.to_string() };
+            let pins = parts.map(parse_pin).collect::<Result<_, _>>()?;
+            Ok(UpstreamAddress::Tls { address, name, pins })
+        } else if upstream.starts_with("https://") {
+            let mut parts = upstream.split('#');
+            let url = parts.next().unwrap_or_default();
+            let address = parts.next().unwrap_or_default();
+            let pins = parts.map(parse_pin).collect::<Result<_, _>>()?;
+            let url: Uri = url.parse().map_err(|e| format!("invalid DNS-over-HTTPS URL {}: {}", url, e))?;
+            let port = url.port_u16().unwrap_or(DOH_PORT);
+            let host = url.host().ok_or_else(|| format!("no host in the DNS-over-HTTPS URL {}", url))?;
+            let address = if address.is_empty() {
+                resolve_host(host, port).await?
+            } else {
+                parse_socket_address(address, port)?
+            };
+            Ok(UpstreamAddress::Https { address, url, pins })
+        } else {
+            Ok(UpstreamAddress::Udp(parse_socket_address(upstream, self.upstream_port)?))
+        }
+    }
+
+    /// Exits after printing `message` as an invalid argument, like the
+    /// errors found while parsing arguments.
+    pub fn exit_with_error(message: &str) -> ! {
+        Args::command().error(ErrorKind::InvalidValue, message).exit()
+    }
+}
+
+/// Resolves the host of a DNS-over-HTTPS URL with the system resolver,
+/// unless it is an IP address.
+///
+/// Hosts are resolved at startup, before queries are answered: if this
+/// server is the system resolver, the lookup fails after
+/// `RESOLVE_TIMEOUT` rather than waiting on itself.
+async fn resolve_host(host: &str, port: u16) -> Result<SocketAddr, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
     }
+    let error = |reason: String| {
+        format!("unable to resolve the DNS-over-HTTPS server {}: {}, give its IP address after #", host, reason)
+    };
+    match tokio::time::timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host((host, port))).await {
+        Ok(Ok(mut addresses)) => addresses.next().ok_or_else(|| error(String::from("no address"))),
+        Ok(Err(e)) => Err(error(e.to_string())),
+        Err(_) => Err(error(String::from("timed out"))),
+    }
+}
+
+/// Reads the base64 SHA-256 digest of a public key.
+fn parse_pin(pin: &str) -> Result<Vec<u8>, String> {
+    STANDARD
+        .decode(pin)
+        .ok()
+        .filter(|pin| pin.len() == 32)
+        .ok_or_else(|| format!("invalid TLS pin {}, expected a base64 SHA-256 digest", pin))
+}
+
+fn parse_socket_address(address: &str, default_port: u16) -> Result<SocketAddr, String> {
+    address
+        .parse::<SocketAddr>()
+        .or_else(|_| address.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, default_port)))
+        .map_err(|_| format!("invalid upstream address {}", address))
+}
+
+/// Upstream server and the protocol to reach it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpstreamAddress {
+    /// Plain DNS server, given without a scheme, on the upstream port unless
+    /// the address has one.
+    Udp(SocketAddr),
+
+    /// DNS-over-TLS, with the name the certificate of the server must be
+    /// valid for and the digests of the public keys it may have, if any.
+    Tls { address: SocketAddr, name: String, pins: Vec<Vec<u8>> },
+
+    /// DNS-over-HTTPS, with the URL queries are posted to and the digests of
+    /// the public keys the server may have, if any.
+    Https { address: SocketAddr, url: Uri, pins: Vec<Vec<u8>> },
 }
@@ -1,118 +1,934 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::RwLock;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use lazy_static::lazy_static;
 use fasthash::farm::Hash64 as HasherFn;
 
+use crate::cli;
+
+use super::compression::LabelTree;
+use super::dto::datagram::Datagram;
+use super::dto::enums::TYPE;
+use super::dto::error::ParseError;
+use super::dto::header::RCODE;
 use super::dto::question::Question;
+use super::dto::rdata::RData;
 use super::dto::resource_record::ResourceRecord;
 
 lazy_static!{
-    static ref CACHE: RwLock<HashMap<Question, (ResourceRecord, Instant), HasherFn>> = RwLock::new(HashMap::with_capacity_and_hasher(16, HasherFn));
-}
-
-pub fn get(question: &Question) -> Option<ResourceRecord> {
-    let hash_map_reader = CACHE.read().expect("Cache lock poisoned");
-    let result: Option<(ResourceRecord, Instant)> = hash_map_reader.get(&question).cloned();
-    drop(hash_map_reader);
-    match result {
-        None => return None,
-        Some((mut rr, instant)) => {
-            let elapsed_time = instant.elapsed().as_secs() as u32;
-            let ttl = rr.get_ttl();
-            if elapsed_time >= ttl {
-                let mut hash_map_writer = CACHE.write().expect("Cache lock poisoned");
-                hash_map_writer.remove(&question);
-                drop(hash_map_writer);
-                return None;
+    static ref CACHE: Shards = {
+        let args = cli::Args::get_params();
+        let prefetch = PrefetchPolicy::from_args(&args);
+        Shards::new(SHARD_COUNT, args.cache_max_entries, args.cache_max_bytes, args.cache_stale_window, prefetch)
+    };
+    static ref POLICY: TtlPolicy = TtlPolicy::from_args(&cli::Args::get_params());
+}
+
+/// Number of independently locked parts of the cache.
+pub const SHARD_COUNT: usize = 16;
+
+/// TTL of records served after they expired (RFC 8767, section 4).
+pub const STALE_TTL: u32 = 30;
+
+/// Query type asking for records of every type (RFC 8482).
+const QTYPE_ANY: u16 = 255;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+/// # Cache key
+///
+/// Question of a query, with its name lowercased as names are compared
+/// without regard to case (RFC 4343), and the flags that change the
+/// content of replies: DNSSEC OK (RFC 3225) and Checking Disabled (RFC 4035).
+pub struct Key {
+    pub question: Question,
+    pub dnssec_ok: bool,
+    pub checking_disabled: bool,
+}
+
+impl Key {
+    pub fn new(question: &Question, dnssec_ok: bool, checking_disabled: bool) -> Self {
+        let question = Question {
+            qname: question.qname.to_lowercase(),
+            content: question.content,
+        };
+        Self {
+            question,
+            dnssec_ok,
+            checking_disabled,
+        }
+    }
+
+    /// Key of the first question of a query.
+    pub fn from_query(datagram: &Datagram) -> Self {
+        Self::new(
+            &datagram.questions[0],
+            datagram.get_opt().is_some_and(|opt| opt.is_dnssec_ok()),
+            datagram.header.is_checked_data(),
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+/// # TTL policy
+///
+/// Bounds applied to the TTLs sent by upstream servers, so that entries are
+/// neither refreshed too often nor kept too long.
+pub struct TtlPolicy {
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+
+    /// Used instead of `max_ttl` for negative answers.
+    pub max_negative_ttl: u32,
+
+    /// Whether replies with a TTL of 0 are cached for `min_ttl`, rather than
+    /// only used for the query that asked for them.
+    pub cache_zero_ttl: bool,
+}
+
+impl TtlPolicy {
+    pub fn from_args(args: &cli::Args) -> Self {
+        Self {
+            min_ttl: args.cache_min_ttl,
+            max_ttl: args.cache_max_ttl,
+            max_negative_ttl: args.cache_max_negative_ttl,
+            cache_zero_ttl: args.cache_zero_ttl,
+        }
+    }
+
+    /// The maximum wins over the minimum if they overlap.
+    fn clamp(&self, ttl: u32, is_negative: bool) -> u32 {
+        let max_ttl = if is_negative { self.max_negative_ttl } else { self.max_ttl };
+        ttl.max(self.min_ttl).min(max_ttl)
+    }
+}
+
+#[derive(Clone, Debug)]
+/// # Prefetch policy
+///
+/// Entries read at least `min_hits` times are refreshed in the background
+/// when read within the last `threshold` percent of their TTL, so that
+/// popular entries do not expire, like the prefetch option of Unbound.
+pub struct PrefetchPolicy {
+    /// Percentage of the TTL, 0 to disable prefetching.
+    pub threshold: u32,
+    pub min_hits: u32,
+}
+
+impl PrefetchPolicy {
+    pub fn from_args(args: &cli::Args) -> Self {
+        Self {
+            threshold: args.prefetch_threshold.min(100),
+            min_hits: args.prefetch_min_hits,
+        }
+    }
+
+    fn is_due(&self, hits: u32, ttl: u32, remaining_ttl: u32) -> bool {
+        self.threshold > 0
+            && hits >= self.min_hits
+            && remaining_ttl as u64 * 100 <= ttl as u64 * self.threshold as u64
+    }
+}
+
+#[derive(Clone)]
+/// # Cache entry
+///
+/// Sections of an upstream reply, stored whole so that RRsets, CNAME chains
+/// and negative answers (RFC 2308) are served as they were received.
+pub struct Entry {
+    pub rcode: RCODE,
+    pub answers: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    pub additionals: Vec<ResourceRecord>,
+    ttl: u32,
+
+    /// Set on the copy returned by `get` to the first reader that should
+    /// refresh the entry.
+    prefetch: bool,
+}
+
+impl Entry {
+    /// Builds an entry from an upstream reply.
+    ///
+    /// Returns `None` if the reply must not be cached: errors other than
+    /// NXDOMAIN, negative answers without a SOA record to bound them, or
+    /// replies with a TTL of 0 unless the policy allows it.
+    ///
+    /// The TTL of the entry and of its records are bounded by `policy`.
+    pub fn from_reply(reply: &Datagram, policy: &TtlPolicy) -> Option<Self> {
+        let rcode = reply.header.get_rcode();
+        let additionals: Vec<ResourceRecord> = reply
+            .additionals
+            .iter()
+            .filter(|rr| rr.get_type() != TYPE::OPT)
+            .cloned()
+            .collect();
+
+        // A CNAME chain may lead to a name that does not exist or has no
+        // record of the queried type (RFC 2308, section 2).
+        let is_negative = rcode == RCODE::NXDomain || !Self::has_answer(reply);
+        let ttl = match rcode {
+            RCODE::NoError | RCODE::NXDomain if is_negative => reply
+                .answers
+                .iter()
+                .map(ResourceRecord::get_ttl)
+                .fold(Self::get_negative_ttl(reply)?, u32::min),
+            RCODE::NoError => reply
+                .answers
+                .iter()
+                .chain(&reply.authorities)
+                .chain(&additionals)
+                .map(ResourceRecord::get_ttl)
+                .min()?,
+            _ => return None,
+        };
+        if ttl == 0 && !policy.cache_zero_ttl {
+            return None;
+        }
+
+        let mut entry = Self {
+            rcode,
+            answers: reply.answers.clone(),
+            authorities: reply.authorities.clone(),
+            additionals,
+            ttl: policy.clamp(ttl, is_negative),
+            prefetch: false,
+        };
+        entry
+            .answers
+            .iter_mut()
+            .chain(entry.authorities.iter_mut())
+            .chain(entry.additionals.iter_mut())
+            .for_each(|rr| rr.set_ttl(policy.clamp(rr.get_ttl(), is_negative)));
+        // A zero minimum TTL leaves nothing to cache.
+        (entry.ttl > 0).then_some(entry)
+    }
+
+    /// Whether the reply has a record of the queried type, rather than
+    /// none or only the CNAME records leading to it.
+    fn has_answer(reply: &Datagram) -> bool {
+        let Some(question) = reply.questions.first() else {
+            return !reply.answers.is_empty();
+        };
+        let qtype = question.get_type();
+        reply
+            .answers
+            .iter()
+            .any(|rr| qtype.to_u16() == QTYPE_ANY || rr.get_type() == qtype)
+    }
+
+    /// TTL of a negative answer, the lowest of the SOA TTL and its MINIMUM
+    /// field (RFC 2308, section 5).
+    fn get_negative_ttl(reply: &Datagram) -> Option<u32> {
+        reply.authorities.iter().find_map(|rr| match rr.rdata {
+            RData::SOA { minimum, .. } => Some(rr.get_ttl().min(minimum)),
+            _ => None,
+        })
+    }
+
+    /// Approximate size of the entry, counting records as written
+    /// without compression.
+    fn get_size(&self) -> usize {
+        self.answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+            .map(|rr| rr.name.get_string().len() + 2 + 10 + rr.get_rdata_length())
+            .sum()
+    }
+
+    /// Whether the entry should be refreshed from upstream in the background.
+    pub fn needs_prefetch(&self) -> bool {
+        self.prefetch
+    }
+
+    /// Sets the TTL of every record to the TTL of stale records.
+    fn set_stale(&mut self) {
+        self.ttl = STALE_TTL;
+        self.answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.additionals.iter_mut())
+            .for_each(|rr| rr.set_ttl(STALE_TTL));
+    }
+
+    pub fn get_ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// Decrements the TTL of every record by `elapsed` seconds.
+    pub fn age(&mut self, elapsed: u32) {
+        self.ttl -= elapsed;
+        self.answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.additionals.iter_mut())
+            .for_each(|rr| rr.set_ttl(rr.get_ttl().saturating_sub(elapsed)));
+    }
+}
+
+impl Entry {
+    /// Length of the RCODE, TTL and section counts.
+    const FIELDS_LENGTH: usize = 11;
+
+    /// Writes the RCODE, the TTL and the section counts of the entry,
+    /// followed by its records in the wire format.
+    pub fn serialize(&self, bytes: &mut Vec<u8>, lt: &mut LabelTree) {
+        bytes.push(self.rcode.to_u8());
+        bytes.extend_from_slice(&self.ttl.to_be_bytes());
+        for section in [&self.answers, &self.authorities, &self.additionals] {
+            bytes.extend_from_slice(&(section.len() as u16).to_be_bytes());
+        }
+        for rr in self.answers.iter().chain(&self.authorities).chain(&self.additionals) {
+            rr.serialize(bytes, lt);
+        }
+    }
+
+    pub fn unserialize(stream: &[u8], offset: u16) -> Result<(Self, u16), ParseError> {
+        let start = offset as usize;
+        let fields = stream.get(start..start + Self::FIELDS_LENGTH).ok_or(ParseError::Truncated)?;
+        let rcode = RCODE::from_u8(fields[0]);
+        let ttl = u32::from_be_bytes(fields[1..5].try_into().unwrap());
+        let counts = [5, 7, 9].map(|i| u16::from_be_bytes([fields[i], fields[i + 1]]));
+
+        let mut offset = offset + Self::FIELDS_LENGTH as u16;
+        let mut sections: [Vec<ResourceRecord>; 3] = Default::default();
+        for (section, count) in sections.iter_mut().zip(counts) {
+            for _ in 0..count {
+                let (rr, next_offset) = ResourceRecord::unserialize(stream, offset)?;
+                section.push(rr);
+                offset = next_offset;
+            }
+        }
+
+        let [answers, authorities, additionals] = sections;
+        let entry = Self {
+            rcode,
+            answers,
+            authorities,
+            additionals,
+            ttl,
+            prefetch: false,
+        };
+        Ok((entry, offset))
+    }
+}
+
+struct Slot {
+    entry: Entry,
+    inserted: Instant,
+    size: usize,
+
+    /// Set when the entry is read, cleared when the clock hand passes it.
+    referenced: AtomicBool,
+
+    /// Number of times the entry was read before it expired.
+    hits: AtomicU32,
+
+    /// Set once a reader was asked to refresh the entry.
+    prefetching: AtomicBool,
+
+    /// Tells this slot apart from older ones stored under the same key,
+    /// whose place in the clock is stale.
+    generation: u64,
+}
+
+impl Slot {
+    /// Whether the entry expired more than `stale_window` seconds ago.
+    fn is_expired(&self, stale_window: u32) -> bool {
+        self.inserted.elapsed().as_secs() >= self.entry.ttl as u64 + stale_window as u64
+    }
+}
+
+/// # Store
+///
+/// Entries bounded by a number of entries and a byte budget, a limit of 0
+/// meaning unbounded.
+///
+/// ## Eviction
+///
+/// Entries are evicted with the CLOCK algorithm, an approximation of LRU:
+/// reads only mark an entry as referenced, so that they do not need the
+/// write lock. When a limit is exceeded, the hand goes through entries in
+/// insertion order, giving referenced entries a second chance and evicting
+/// the first one that was not read since the hand last passed. Expired
+/// entries never get a second chance.
+///
+/// ## Stale entries
+///
+/// Expired entries are kept for `stale_window` seconds, to be served if
+/// upstream servers cannot be reached (RFC 8767).
+struct Store {
+    map: HashMap<Key, Slot, HasherFn>,
+    clock: VecDeque<(Key, u64)>,
+    bytes: usize,
+    generation: u64,
+    max_entries: usize,
+    max_bytes: usize,
+    stale_window: u32,
+}
+
+impl Store {
+    fn new(max_entries: usize, max_bytes: usize, stale_window: u32) -> Self {
+        Self {
+            map: HashMap::with_capacity_and_hasher(16, HasherFn),
+            clock: VecDeque::with_capacity(16),
+            bytes: 0,
+            generation: 0,
+            max_entries,
+            max_bytes,
+            stale_window,
+        }
+    }
+
+    fn get(&self, key: &Key) -> Option<&Slot> {
+        let slot = self.map.get(key)?;
+        slot.referenced.store(true, Ordering::Relaxed);
+        Some(slot)
+    }
+
+    fn insert(&mut self, key: Key, entry: Entry) {
+        let size = entry.get_size();
+        if self.max_bytes > 0 && size > self.max_bytes {
+            return;
+        }
+        self.remove(&key);
+        self.generation += 1;
+        self.bytes += size;
+        self.clock.push_back((key.clone(), self.generation));
+        self.map.insert(
+            key,
+            Slot {
+                entry,
+                inserted: Instant::now(),
+                size,
+                referenced: AtomicBool::new(false),
+                hits: AtomicU32::new(0),
+                prefetching: AtomicBool::new(false),
+                generation: self.generation,
+            },
+        );
+        self.evict();
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Slot> {
+        let slot = self.map.remove(key)?;
+        self.bytes -= slot.size;
+        Some(slot)
+    }
+
+    fn is_full(&self) -> bool {
+        (self.max_entries > 0 && self.map.len() > self.max_entries)
+            || (self.max_bytes > 0 && self.bytes > self.max_bytes)
+    }
+
+    fn evict(&mut self) {
+        while self.is_full() {
+            let Some((key, generation)) = self.clock.pop_front() else {
+                break;
+            };
+            let Some(slot) = self.map.get(&key).filter(|slot| slot.generation == generation) else {
+                continue;
+            };
+            if slot.referenced.swap(false, Ordering::Relaxed) && !slot.is_expired(0) {
+                self.clock.push_back((key, generation));
             } else {
-                rr.set_ttl(ttl - elapsed_time);
-                return Some(rr);
+                log::debug!("Evicting {} from cache", key.question.qname);
+                self.remove(&key);
             }
         }
     }
+
+    /// Removes entries expired for longer than the stale window, and the
+    /// places of removed entries in the clock.
+    fn sweep(&mut self) -> usize {
+        let count = self.map.len();
+        let stale_window = self.stale_window;
+        self.map.retain(|_, slot| !slot.is_expired(stale_window));
+        self.bytes = self.map.values().map(|slot| slot.size).sum();
+        let map = &self.map;
+        self.clock
+            .retain(|(key, generation)| map.get(key).is_some_and(|slot| slot.generation == *generation));
+        count - self.map.len()
+    }
 }
 
-pub fn insert(question: &Question, rr: ResourceRecord) {
-    let mut hash_map_writer = CACHE.write().expect("Cache lock poisoned");
-    hash_map_writer.insert(question.clone(), (rr, Instant::now()));
+/// # Shards
+///
+/// Stores spread by the hash of their key, each behind its own lock,
+/// so that concurrent queries rarely wait on each other.
+///
+/// Reads only take a read lock: expired entries are not removed when read,
+/// but when evicted or swept. Limits are split evenly between shards.
+pub struct Shards {
+    shards: Vec<RwLock<Store>>,
+    prefetch: PrefetchPolicy,
+}
+
+impl Shards {
+    pub fn new(count: usize, max_entries: usize, max_bytes: usize, stale_window: u32, prefetch: PrefetchPolicy) -> Self {
+        let shards = (0..count)
+            .map(|_| {
+                let store = Store::new(max_entries.div_ceil(count), max_bytes.div_ceil(count), stale_window);
+                RwLock::new(store)
+            })
+            .collect();
+        Self { shards, prefetch }
+    }
+
+    fn get_shard(&self, key: &Key) -> &RwLock<Store> {
+        &self.shards[HasherFn.hash_one(key) as usize % self.shards.len()]
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Entry> {
+        let store_reader = self.get_shard(key).read().expect("Cache lock poisoned");
+        let slot = store_reader.get(key)?;
+        let elapsed_time = slot.inserted.elapsed().as_secs() as u32;
+        if elapsed_time >= slot.entry.ttl {
+            return None;
+        }
+        let hits = slot.hits.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut entry = slot.entry.clone();
+        entry.prefetch = self.prefetch.is_due(hits, entry.ttl, entry.ttl - elapsed_time)
+            && !slot.prefetching.swap(true, Ordering::Relaxed);
+        drop(store_reader);
+        entry.age(elapsed_time);
+        Some(entry)
+    }
+
+    /// Like `get`, but also returns entries within the stale window, with
+    /// the TTL of stale records.
+    fn get_stale(&self, key: &Key) -> Option<Entry> {
+        let store_reader = self.get_shard(key).read().expect("Cache lock poisoned");
+        let slot = store_reader.get(key)?;
+        if slot.is_expired(store_reader.stale_window) {
+            return None;
+        }
+        let elapsed_time = slot.inserted.elapsed().as_secs() as u32;
+        let mut entry = slot.entry.clone();
+        drop(store_reader);
+        if elapsed_time >= entry.ttl {
+            entry.set_stale();
+        } else {
+            entry.age(elapsed_time);
+        }
+        Some(entry)
+    }
+
+    pub fn insert(&self, key: &Key, entry: Entry) {
+        let mut store_writer = self.get_shard(key).write().expect("Cache lock poisoned");
+        store_writer.insert(key.clone(), entry);
+    }
+
+    /// Sweeps shards one at a time, returning the number of removed entries,
+    /// and the number of entries and bytes left.
+    fn sweep(&self) -> (usize, usize, usize) {
+        self.shards.iter().fold((0, 0, 0), |(count, entries, bytes), shard| {
+            let mut store_writer = shard.write().expect("Cache lock poisoned");
+            let swept = store_writer.sweep();
+            (count + swept, entries + store_writer.map.len(), bytes + store_writer.bytes)
+        })
+    }
+
+    /// Copies every entry that has not expired, with its TTLs counted down.
+    fn export(&self) -> Vec<(Key, Entry)> {
+        let mut entries: Vec<(Key, Entry)> = Vec::new();
+        for shard in self.shards.iter() {
+            let store_reader = shard.read().expect("Cache lock poisoned");
+            for (key, slot) in store_reader.map.iter().filter(|(_, slot)| !slot.is_expired(0)) {
+                let mut entry = slot.entry.clone();
+                entry.age(slot.inserted.elapsed().as_secs() as u32);
+                entries.push((key.clone(), entry));
+            }
+        }
+        entries
+    }
+
+    fn reset(&self) {
+        for shard in self.shards.iter() {
+            let mut store = shard.write().expect("Cache lock poisoned");
+            *store = Store::new(store.max_entries, store.max_bytes, store.stale_window);
+        }
+    }
+}
+
+pub fn get(key: &Key) -> Option<Entry> {
+    CACHE.get(key)
+}
+
+/// Returns the entry of `key` even if it expired, as long as it is
+/// within the stale window.
+///
+/// Only meant for when upstream servers cannot be reached.
+pub fn get_stale(key: &Key) -> Option<Entry> {
+    CACHE.get_stale(key)
+}
+
+/// Stores the reply to the query of `key`, if it can be cached.
+pub fn insert(key: &Key, reply: &Datagram) {
+    if let Some(entry) = Entry::from_reply(reply, &POLICY) {
+        CACHE.insert(key, entry);
+    }
+}
+
+/// Removes expired entries every `interval`, so that entries which are
+/// not asked for again do not stay in memory.
+pub async fn sweep(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let (count, entries, bytes) = CACHE.sweep();
+        log::debug!("Swept {} expired cache entries, {} left using {} bytes", count, entries, bytes);
+    }
+}
+
+/// Copies every entry that has not expired, with its TTLs counted down.
+pub fn export() -> Vec<(Key, Entry)> {
+    CACHE.export()
+}
+
+/// Stores an exported entry, without applying the TTL policy again.
+pub fn import(key: &Key, entry: Entry) {
+    CACHE.insert(key, entry);
 }
 
 #[allow(dead_code)]
 pub fn reset() {
     log::info!("Resetting cache");
-    let mut hash = CACHE.write().expect("Cache lock poisoned");
-    *hash = HashMap::with_capacity_and_hasher(128, HasherFn);
+    CACHE.reset();
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use crate::dns::{
-        dto::name::Name,
-        compression::LabelTree
+    use crate::dns::dto::{
+        enums::CLASS,
+        header::Header,
+        name::Name,
+        opt::Opt,
     };
+    use std::net::Ipv4Addr;
     use std::thread::sleep;
-    use std::time::Duration;
-    use std::sync::Mutex;
-    use std::marker::PhantomData;
-    
-    static MUTEX: Mutex<PhantomData<()>> = Mutex::new(PhantomData {});
+
+    fn question(name: &str) -> Question {
+        Question {
+            qname: Name::from(name),
+            content: [0, 1, 0, 1],
+        }
+    }
+
+    fn key(name: &str) -> Key {
+        Key::new(&question(name), false, false)
+    }
+
+    fn record(name: &str, rtype: TYPE, ttl: u32, rdata: RData) -> ResourceRecord {
+        ResourceRecord::new(Name::from(name), rtype, CLASS::IN, ttl, rdata)
+    }
+
+    fn reply(rcode: RCODE, answers: Vec<ResourceRecord>, authorities: Vec<ResourceRecord>) -> Datagram {
+        let mut header = Header::new_reply();
+        header.set_rcode(rcode);
+        let mut datagram = Datagram {
+            header,
+            questions: vec![question("google.com")],
+            answers,
+            authorities,
+            additionals: vec![Opt::new(1232).to_record()],
+        };
+        datagram.update_counts();
+        datagram
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> ResourceRecord {
+        let rdata = RData::SOA {
+            mname: Name::from("ns.google.com"),
+            rname: Name::from("dns-admin.google.com"),
+            serial: 1,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum,
+        };
+        record("google.com", TYPE::SOA, ttl, rdata)
+    }
 
     #[test]
     fn test_cache_basic() {
-        let _lock = MUTEX.lock();
-        reset();
-        let question = Question {
-            qname: Name::from("google.com"),
-            content: [0, 1, 0, 1],
+        let answers = vec![
+            record("www.google.com", TYPE::CNAME, 300, RData::CNAME(Name::from("google.com"))),
+            record("google.com", TYPE::A, 10, RData::A(Ipv4Addr::new(8, 8, 8, 8))),
+            record("google.com", TYPE::A, 10, RData::A(Ipv4Addr::new(8, 8, 4, 4))),
+        ];
+        insert(&key("basic.google.com"), &reply(RCODE::NoError, answers.clone(), vec![]));
+
+        // The whole RRset and chain is kept, in order, without the OPT record
+        let entry = get(&key("basic.google.com")).unwrap();
+        assert_eq!(entry.answers, answers);
+        assert_eq!(entry.ttl, 10);
+        assert!(entry.additionals.is_empty());
+
+        assert!(get(&key("bing.com")).is_none());
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let answers = vec![record("www.google.com", TYPE::A, 10, RData::A(Ipv4Addr::new(8, 8, 8, 8)))];
+        insert(&key("key.google.com"), &reply(RCODE::NoError, answers, vec![]));
+        assert!(get(&key("Key.GOOGLE.com")).is_some());
+
+        // Replies depend on the DNSSEC flags of the query
+        assert!(get(&Key::new(&question("key.google.com"), true, false)).is_none());
+        assert!(get(&Key::new(&question("key.google.com"), false, true)).is_none());
+
+        // And on its class
+        let mut chaos = question("key.google.com");
+        chaos.content = [0, 1, 0, 3];
+        assert!(get(&Key::new(&chaos, false, false)).is_none());
+    }
+
+    #[test]
+    fn test_cache_negative() {
+        insert(&key("negative.google.com"), &reply(RCODE::NXDomain, vec![], vec![soa(3600, 60)]));
+        let entry = get(&key("negative.google.com")).unwrap();
+        assert!(entry.rcode == RCODE::NXDomain);
+        assert_eq!(entry.ttl, 60);
+
+        // NODATA, bounded by the SOA TTL
+        insert(&key("negative.google.com"), &reply(RCODE::NoError, vec![], vec![soa(30, 60)]));
+        let entry = get(&key("negative.google.com")).unwrap();
+        assert!(entry.rcode == RCODE::NoError);
+        assert_eq!(entry.ttl, 30);
+
+        // Without a SOA, negative answers are not cached
+        insert(&key("nosoa.google.com"), &reply(RCODE::NXDomain, vec![], vec![]));
+        assert!(get(&key("nosoa.google.com")).is_none());
+
+        insert(&key("nosoa.google.com"), &reply(RCODE::ServFail, vec![], vec![]));
+        assert!(get(&key("nosoa.google.com")).is_none());
+    }
+
+    #[test]
+    fn test_negative_cname_chain() {
+        let policy = TtlPolicy {
+            min_ttl: 0,
+            max_ttl: 3600,
+            max_negative_ttl: 300,
+            cache_zero_ttl: false,
         };
-        let mut answer_byte = Vec::new();
-        let mut tree = LabelTree::default();
-        let name = Name::from("google.com");
-        name.serialize(&mut answer_byte, &mut tree);
-        answer_byte.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 10, 0, 4, 8, 8, 8, 8]);
-        let (answer, _) = ResourceRecord::unserialize(&answer_byte, 0);
-        insert(&question, answer.clone());
-
-        let reply: Option<ResourceRecord>;
-        reply = get(&question);
-        assert_eq!(reply.unwrap(), answer);
+        let chain = vec![record("google.com", TYPE::CNAME, 86400, RData::CNAME(Name::from("www.google.com")))];
 
-        let question = Question {
-            qname: Name::from("bing.com"),
-            content: [0, 1, 0, 1],
+        // NXDOMAIN and NODATA at the end of the chain are negative answers
+        for rcode in [RCODE::NXDomain, RCODE::NoError] {
+            let entry = Entry::from_reply(&reply(rcode, chain.clone(), vec![soa(86400, 3600)]), &policy).unwrap();
+            assert_eq!(entry.ttl, 300);
+            assert_eq!(entry.answers[0].get_ttl(), 300);
+        }
+        // Which are bounded by the TTL of the chain
+        let chain = vec![record("google.com", TYPE::CNAME, 10, RData::CNAME(Name::from("www.google.com")))];
+        let entry = Entry::from_reply(&reply(RCODE::NXDomain, chain.clone(), vec![soa(86400, 3600)]), &policy).unwrap();
+        assert_eq!(entry.ttl, 10);
+        // And need a SOA record
+        assert!(Entry::from_reply(&reply(RCODE::NoError, chain, vec![]), &policy).is_none());
+
+        // The chain leads to an answer of the queried type
+        let answers = vec![
+            record("google.com", TYPE::CNAME, 86400, RData::CNAME(Name::from("www.google.com"))),
+            record("www.google.com", TYPE::A, 86400, RData::A(Ipv4Addr::new(8, 8, 8, 8))),
+        ];
+        let entry = Entry::from_reply(&reply(RCODE::NoError, answers, vec![]), &policy).unwrap();
+        assert_eq!(entry.ttl, 3600);
+    }
+
+    fn entry(address: u8, ttl: u32) -> Entry {
+        let answers = vec![record("google.com", TYPE::A, ttl, RData::A(Ipv4Addr::new(8, 8, 8, address)))];
+        Entry::from_reply(&reply(RCODE::NoError, answers, vec![]), &POLICY).unwrap()
+    }
+
+    #[test]
+    fn test_ttl_policy() {
+        let policy = TtlPolicy {
+            min_ttl: 60,
+            max_ttl: 3600,
+            max_negative_ttl: 300,
+            cache_zero_ttl: false,
         };
+        let answers = |ttl: u32| vec![
+            record("google.com", TYPE::A, ttl, RData::A(Ipv4Addr::new(8, 8, 8, 8))),
+            record("google.com", TYPE::A, ttl * 2, RData::A(Ipv4Addr::new(8, 8, 4, 4))),
+        ];
+
+        let entry = Entry::from_reply(&reply(RCODE::NoError, answers(10), vec![]), &policy).unwrap();
+        assert_eq!(entry.ttl, 60);
+        assert_eq!(entry.answers[0].get_ttl(), 60);
+
+        let entry = Entry::from_reply(&reply(RCODE::NoError, answers(86400), vec![]), &policy).unwrap();
+        assert_eq!(entry.ttl, 3600);
+        assert_eq!(entry.answers[1].get_ttl(), 3600);
+
+        let entry = Entry::from_reply(&reply(RCODE::NXDomain, vec![], vec![soa(86400, 3600)]), &policy).unwrap();
+        assert_eq!(entry.ttl, 300);
+        assert_eq!(entry.authorities[0].get_ttl(), 300);
+
+        // TTL of 0, only cached when allowed
+        assert!(Entry::from_reply(&reply(RCODE::NoError, answers(0), vec![]), &policy).is_none());
+        let policy = TtlPolicy { cache_zero_ttl: true, ..policy };
+        let entry = Entry::from_reply(&reply(RCODE::NoError, answers(0), vec![]), &policy).unwrap();
+        assert_eq!(entry.ttl, 60);
+        let policy = TtlPolicy { min_ttl: 0, ..policy };
+        assert!(Entry::from_reply(&reply(RCODE::NoError, answers(0), vec![]), &policy).is_none());
+    }
+
+    #[test]
+    fn test_entry_serialize() {
+        let answers = vec![
+            record("www.google.com", TYPE::CNAME, 300, RData::CNAME(Name::from("google.com"))),
+            record("google.com", TYPE::A, 10, RData::A(Ipv4Addr::new(8, 8, 8, 8))),
+        ];
+        let entry = Entry::from_reply(&reply(RCODE::NXDomain, answers, vec![soa(60, 60)]), &POLICY).unwrap();
+        let mut bytes = Vec::new();
+        entry.serialize(&mut bytes, &mut LabelTree::default());
+
+        let (parsed, offset) = Entry::unserialize(&bytes, 0).unwrap();
+        assert_eq!(offset as usize, bytes.len());
+        assert!(parsed.rcode == RCODE::NXDomain);
+        assert_eq!(parsed.ttl, entry.ttl);
+        assert_eq!(parsed.answers, entry.answers);
+        assert_eq!(parsed.authorities, entry.authorities);
+        assert!(parsed.additionals.is_empty());
+
+        assert_eq!(Entry::unserialize(&bytes[..bytes.len() - 1], 0).err(), Some(ParseError::Truncated));
+    }
+
+    #[test]
+    fn test_store_max_entries() {
+        let mut store = Store::new(2, 0, 0);
+        store.insert(key("a.com"), entry(1, 10));
+        store.insert(key("b.com"), entry(2, 10));
+
+        // Read entries get a second chance
+        assert!(store.get(&key("a.com")).is_some());
+        store.insert(key("c.com"), entry(3, 10));
+        assert_eq!(store.map.len(), 2);
+        assert!(store.get(&key("a.com")).is_some());
+        assert!(store.get(&key("b.com")).is_none());
+        assert!(store.get(&key("c.com")).is_some());
+
+        // Replacing an entry does not count twice
+        store.insert(key("c.com"), entry(4, 10));
+        store.insert(key("c.com"), entry(5, 10));
+        assert_eq!(store.map.len(), 2);
+        assert!(store.get(&key("a.com")).is_some());
+    }
+
+    #[test]
+    fn test_store_max_bytes() {
+        let size = entry(1, 10).get_size();
+        let mut store = Store::new(0, size * 2, 0);
+        store.insert(key("a.com"), entry(1, 10));
+        store.insert(key("b.com"), entry(2, 10));
+        store.insert(key("c.com"), entry(3, 10));
+        assert_eq!(store.map.len(), 2);
+        assert_eq!(store.bytes, size * 2);
+        assert!(store.get(&key("a.com")).is_none());
+
+        // Entries larger than the whole budget are not stored
+        let mut store = Store::new(0, size - 1, 0);
+        store.insert(key("a.com"), entry(1, 10));
+        assert!(store.map.is_empty());
+        assert_eq!(store.bytes, 0);
+    }
+
+    #[test]
+    fn test_store_sweep() {
+        let mut store = Store::new(0, 0, 0);
+        store.insert(key("a.com"), entry(1, 1));
+        store.insert(key("b.com"), entry(2, 10));
+        store.insert(key("b.com"), entry(3, 10));
+        assert_eq!(store.clock.len(), 3);
+
+        sleep(Duration::from_millis(1010));
+
+        assert_eq!(store.sweep(), 1);
+        assert_eq!(store.map.len(), 1);
+        assert_eq!(store.clock.len(), 1);
+        assert_eq!(store.bytes, entry(3, 10).get_size());
+    }
+
+    fn no_prefetch() -> PrefetchPolicy {
+        PrefetchPolicy { threshold: 0, min_hits: 0 }
+    }
+
+    #[test]
+    fn test_shards_prefetch() {
+        let prefetch = PrefetchPolicy { threshold: 50, min_hits: 2 };
+        let shards = Shards::new(1, 0, 0, 0, prefetch);
+        shards.insert(&key("a.com"), entry(1, 2));
+        assert!(!shards.get(&key("a.com")).unwrap().needs_prefetch());
+
+        // Popular, but not yet within the last half of its TTL
+        assert!(!shards.get(&key("a.com")).unwrap().needs_prefetch());
+
+        // Only one reader refreshes the entry
+        sleep(Duration::from_millis(1010));
+        assert!(shards.get(&key("a.com")).unwrap().needs_prefetch());
+        assert!(!shards.get(&key("a.com")).unwrap().needs_prefetch());
+
+        shards.insert(&key("a.com"), entry(1, 2));
+        assert!(!shards.get(&key("a.com")).unwrap().needs_prefetch());
+    }
 
-        let reply: Option<ResourceRecord>;
-        reply = get(&question);
-        assert!(reply.is_none());
+    #[test]
+    fn test_shards() {
+        let shards = Shards::new(4, 10, 0, 0, no_prefetch());
+        assert!(shards.shards.iter().all(|shard| shard.read().unwrap().max_entries == 3));
+
+        for i in 0..100 {
+            shards.insert(&key(&format!("{}.com", i)), entry(1, 10));
+        }
+        let (_, entries, _) = shards.sweep();
+        assert!(entries <= 12);
+
+        // Expired entries are not served, nor removed, when read
+        shards.reset();
+        shards.insert(&key("a.com"), entry(1, 1));
+        sleep(Duration::from_millis(1010));
+        assert!(shards.get(&key("a.com")).is_none());
+        assert_eq!(shards.sweep(), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_shards_stale() {
+        let shards = Shards::new(1, 0, 0, 1, no_prefetch());
+        shards.insert(&key("a.com"), entry(1, 1));
+        assert_eq!(shards.get_stale(&key("a.com")).unwrap().ttl, 1);
+
+        // Expired, but within the stale window
+        sleep(Duration::from_millis(1010));
+        assert!(shards.get(&key("a.com")).is_none());
+        let entry = shards.get_stale(&key("a.com")).unwrap();
+        assert_eq!(entry.ttl, STALE_TTL);
+        assert_eq!(entry.answers[0].get_ttl(), STALE_TTL);
+        assert_eq!(shards.sweep(), (0, 1, entry.get_size()));
+
+        sleep(Duration::from_millis(1000));
+        assert!(shards.get_stale(&key("a.com")).is_none());
+        assert_eq!(shards.sweep(), (1, 0, 0));
     }
 
     #[test]
     fn test_cache_timeout() {
-        let _lock = MUTEX.lock();
-        reset();
-        let question = Question {
-            qname: Name::from("google.com"),
-            content: [0, 1, 0, 1],
-        };
-        let mut answer_byte = Vec::new();
-        let mut tree = LabelTree::default();
-        let name = Name::from("google.com");
-        name.serialize(&mut answer_byte, &mut tree);
-        answer_byte.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 1, 0, 4, 8, 8, 8, 8]);
-        let (answer, _) = ResourceRecord::unserialize(&answer_byte, 0);
-        insert(&question, answer.clone());
-        let mut reply: Option<ResourceRecord>;
-        reply = get(&question);
-        assert!(reply.is_some());
+        let answers = vec![
+            record("google.com", TYPE::A, 1, RData::A(Ipv4Addr::new(8, 8, 8, 8))),
+            record("google.com", TYPE::A, 10, RData::A(Ipv4Addr::new(8, 8, 4, 4))),
+        ];
+        insert(&key("timeout.google.com"), &reply(RCODE::NoError, answers, vec![]));
+        assert!(get(&key("timeout.google.com")).is_some());
 
         sleep(Duration::from_millis(1010));
 
-        reply = get(&question);
-        println!("{:?}", reply);
-        assert!(reply.is_none());
+        // The RRset expires with its shortest TTL
+        assert!(get(&key("timeout.google.com")).is_none());
     }
 }
@@ -28,6 +28,9 @@ impl Debug for LabelTree {
 impl LabelTree {
     const ROOT_NODE: usize = 0;
 
+    /// Largest position that fits in the 14 bits of a compression pointer.
+    pub const MAX_POINTER: u16 = 0x3FFF;
+
     /// Inserts a list of referenced labels inside the reference tree.
     ///
     /// # Arguments
@@ -82,9 +85,11 @@ impl LabelTree {
         }
     }
 
+    /// Labels are matched with their case, so that pointing to a prior name
+    /// does not change the case of the name being written.
     #[inline]
     fn find_child(&self, parent_index: usize, child: &Label) -> Option<usize> {
-        self.elements[parent_index].children.iter().find(|i| {self.elements[**i].referenced_label.label == *child}).copied()
+        self.elements[parent_index].children.iter().find(|i| {self.elements[**i].referenced_label.label.as_bytes() == child.as_bytes()}).copied()
     }
 
     pub fn find_best_reference(&self, name: &Name) -> CompressionReference {
@@ -98,17 +103,24 @@ impl LabelTree {
             return best_reference;
         }
 
+        let mut depth = 0;
         loop {
-            match self.find_child(node, &name.labels[name.labels.len() - 1 - best_reference.index]) {
+            match self.find_child(node, &name.labels[name.labels.len() - 1 - depth]) {
                 Some(e) => {
-                    best_reference.position = self.elements[e].referenced_label.position;
-                    best_reference.index += 1;
+                    depth += 1;
                     node = e;
+                    // Labels written too far in the datagram cannot be pointed to,
+                    // but the labels under them may still be.
+                    let position = self.elements[e].referenced_label.position;
+                    if position <= Self::MAX_POINTER {
+                        best_reference.position = position;
+                        best_reference.index = depth;
+                    }
                 },
                 None => return best_reference,
             }
 
-            if best_reference.index == name.labels.len() {
+            if depth == name.labels.len() {
                 break;
             }
         }
@@ -213,4 +225,39 @@ mod tests {
         assert_eq!(reference.index, 2);
         assert_eq!(reference.position, 4);
     }
+
+    #[test]
+    fn test_unreachable_reference() {
+        let mut lt: LabelTree = LabelTree::default();
+
+        let referenced_labels = vec![
+            ReferencedLabel::new(Label::from("www"), 0x4010),
+            ReferencedLabel::new(Label::from("antoinec"), 0x4014),
+            ReferencedLabel::new(Label::from("dev"), 0x20),
+        ];
+        lt.insert(referenced_labels);
+
+        let reference = lt.find_best_reference(&Name::from("www.antoinec.dev"));
+
+        assert_eq!(reference.index, 1);
+        assert_eq!(reference.position, 0x20);
+    }
+
+    #[test]
+    fn test_case_sensitive_reference() {
+        let mut lt: LabelTree = LabelTree::default();
+
+        let referenced_labels = vec![
+            ReferencedLabel::new(Label::from("www"), 12),
+            ReferencedLabel::new(Label::from("antoinec"), 16),
+            ReferencedLabel::new(Label::from("dev"), 25),
+        ];
+        lt.insert(referenced_labels);
+
+        // Only the labels with the same case can be pointed to
+        let reference = lt.find_best_reference(&Name::from("WWW.antoinec.dev"));
+
+        assert_eq!(reference.index, 2);
+        assert_eq!(reference.position, 16);
+    }
 }
@@ -2,7 +2,10 @@ use core::fmt;
 
 use crate::dns::compression::LabelTree;
 
+use super::enums::TYPE;
+use super::error::ParseError;
 use super::header::Header;
+use super::opt::Opt;
 use super::question::Question;
 use super::resource_record::ResourceRecord;
 
@@ -30,85 +33,95 @@ pub struct Datagram {
 }
 
 impl Datagram {
-    pub fn unserialize(stream: &[u8]) -> Datagram {
+    pub fn unserialize(stream: &[u8]) -> Result<Datagram, ParseError> {
         let mut offset: u16 = 0;
-        let header = Header::unserialize(stream);
+        let header = Header::unserialize(stream)?;
         offset += Header::LENGTH;
         let mut questions: Vec<Question> = Vec::new();
         for _ in 0..header.question_count() {
+            Self::check_remaining(stream, offset)?;
             let question: Question;
-            (question, offset) = Question::unserialize(stream, offset);
+            (question, offset) = Question::unserialize(stream, offset)?;
             questions.push(question);
         }
         let mut answers: Vec<ResourceRecord> = Vec::new();
         for _ in 0..header.answer_count() {
+            Self::check_remaining(stream, offset)?;
             let answer: ResourceRecord;
-            (answer, offset) = ResourceRecord::unserialize(stream, offset);
+            (answer, offset) = ResourceRecord::unserialize(stream, offset)?;
             answers.push(answer);
         }
         let mut authorities: Vec<ResourceRecord> = Vec::new();
         for _ in 0..header.authority_count() {
+            Self::check_remaining(stream, offset)?;
             let authority: ResourceRecord;
-            (authority, offset) = ResourceRecord::unserialize(stream, offset);
+            (authority, offset) = ResourceRecord::unserialize(stream, offset)?;
             authorities.push(authority);
         }
         let mut additionals: Vec<ResourceRecord> = Vec::new();
         for _ in 0..header.additional_count() {
+            Self::check_remaining(stream, offset)?;
             let additional: ResourceRecord;
-            (additional, offset) = ResourceRecord::unserialize(stream, offset);
+            (additional, offset) = ResourceRecord::unserialize(stream, offset)?;
             additionals.push(additional);
         }
 
-        return Datagram {
+        Ok(Datagram {
             header,
             questions,
             answers,
             authorities,
             additionals,
-        };
+        })
     }
 
-    pub fn serialize(mut self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::with_capacity(512);
-        let mut lt = LabelTree::default();
-        
-        let question_count = self.header.question_count() as usize;
-        let answer_count = self.header.answer_count() as usize;
-        let authority_count = self.header.authority_count() as usize;
-        let additional_count = self.header.additional_count() as usize;
-
-        bytes.extend_from_slice(self.header.serialize().as_slice());
-
-        for _i in 0..question_count {
-            let question = self
-                .questions
-                .pop()
-                .expect("Question index does not exist.");
-            question.serialize(&mut bytes, &mut lt);
+    /// An element that is announced by the header but starts past the end
+    /// of the datagram means the counts are wrong, not that it was cut.
+    #[inline]
+    fn check_remaining(stream: &[u8], offset: u16) -> Result<(), ParseError> {
+        if offset as usize >= stream.len() {
+            return Err(ParseError::BadCount);
         }
+        Ok(())
+    }
 
-        for _i in 0..answer_count {
-            let answer = self
-                .answers
-                .pop()
-                .expect("Answer index does not exist.");
-            answer.serialize(&mut bytes, &mut lt);
+    /// Sets the section counts of the header from the content of the datagram.
+    pub fn update_counts(&mut self) {
+        self.header.set_question_count(self.questions.len() as u16);
+        self.header.set_answer_count(self.answers.len() as u16);
+        self.header.set_authority_count(self.authorities.len() as u16);
+        self.header.set_additional_count(self.additionals.len() as u16);
+    }
+
+    /// Returns the EDNS(0) parameters of the datagram, if any.
+    pub fn get_opt(&self) -> Option<Opt> {
+        self.additionals.iter().find_map(Opt::from_record)
+    }
+
+    /// Replaces the OPT record of the datagram, or removes it if `opt` is `None`.
+    pub fn set_opt(&mut self, opt: Option<Opt>) {
+        self.additionals.retain(|rr| rr.get_type() != TYPE::OPT);
+        if let Some(opt) = opt {
+            self.additionals.push(opt.to_record());
         }
+        self.header.set_additional_count(self.additionals.len() as u16);
+    }
+
+    /// Writes the datagram, keeping the order of every section.
+    ///
+    /// The header is written as is, see `update_counts`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(512);
+        let mut lt = LabelTree::default();
 
-        for _i in 0..authority_count {
-            let authority = self
-                .authorities
-                .pop()
-                .expect("Authority index does not exist.");
-            authority.serialize(&mut bytes, &mut lt);
+        bytes.extend_from_slice(&self.header.serialize());
+
+        for question in self.questions.iter() {
+            question.serialize(&mut bytes, &mut lt);
         }
 
-        for _i in 0..additional_count {
-            let additional = self
-                .additionals
-                .pop()
-                .expect("Question index does not exist.");
-            additional.serialize(&mut bytes, &mut lt);
+        for rr in self.answers.iter().chain(&self.authorities).chain(&self.additionals) {
+            rr.serialize(&mut bytes, &mut lt);
         }
 
         log::debug!("{:?}", lt);
@@ -176,8 +189,123 @@ mod tests {
             0x00, 0x01, 0x00, 0x01, // answer 1
             0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x68, 0x00, 0x04, 127, 0, 0, 1,
         ];
-        let datagram = Datagram::unserialize(&datagram_bytes);
+        let datagram = Datagram::unserialize(&datagram_bytes).unwrap();
         
         assert_eq!(datagram.serialize().as_slice(), datagram_bytes);
     }
+
+    #[test]
+    fn section_order_test() {
+        let datagram_bytes = [
+            // header
+            0x44, 0x44, 0b10000000, 0b00000000, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+            // question 1
+            3, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+            // answer 1
+            0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x68, 0x00, 0x04, 127, 0, 0, 1,
+            // answer 2
+            0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x68, 0x00, 0x04, 127, 0, 0, 2,
+        ];
+        let datagram = Datagram::unserialize(&datagram_bytes).unwrap();
+
+        // Serializing does not consume the datagram, and gives the same result each time
+        assert_eq!(datagram.serialize().as_slice(), datagram_bytes);
+        assert_eq!(datagram.serialize().as_slice(), datagram_bytes);
+        assert_eq!(datagram.answers[0].rdata.to_string(), "127.0.0.1");
+    }
+
+    #[test]
+    fn opt_test() {
+        let datagram_bytes = [
+            // header
+            0x44, 0x44, 0b00000001, 0b00000000, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            // question 1
+            3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0x00,
+            0x00, 0x01, 0x00, 0x01, // additional 1
+            0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+        ];
+        let mut datagram = Datagram::unserialize(&datagram_bytes).unwrap();
+        let opt = datagram.get_opt().unwrap();
+        assert_eq!(opt.udp_payload_size, 4096);
+        assert!(opt.is_dnssec_ok());
+
+        datagram.set_opt(Some(Opt::new(1232)));
+        assert_eq!(datagram.header.additional_count(), 1);
+        assert_eq!(datagram.get_opt().unwrap().udp_payload_size, 1232);
+
+        datagram.set_opt(None);
+        assert_eq!(datagram.header.additional_count(), 0);
+        assert!(datagram.get_opt().is_none());
+        assert_eq!(datagram.serialize().len(), datagram_bytes.len() - 11);
+    }
+
+    #[test]
+    fn compressed_rdata_round_trip_test() {
+        let datagram_bytes = [
+            // header
+            0x44, 0x44, 0b10000000, 0b00000000, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+            // question 1
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0x00,
+            0x00, 0x01, 0x00, 0x01,
+            // answer 1, CNAME with its target compressed
+            0xC0, 0x0C, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x68, 0x00, 0x06,
+            3, b'w', b'e', b'b', 0xC0, 0x10,
+            // answer 2, named after the CNAME target
+            0xC0, 0x2D, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x68, 0x00, 0x04, 127, 0, 0, 1,
+        ];
+        let datagram = Datagram::unserialize(&datagram_bytes).unwrap();
+        let names = |datagram: &Datagram| {
+            let mut names: Vec<String> = datagram
+                .answers
+                .iter()
+                .map(|answer| format!("{} {}", answer.name, answer.rdata))
+                .collect();
+            names.sort();
+            names
+        };
+        let expected = names(&datagram);
+        assert_eq!(expected, ["web.example.com 127.0.0.1", "www.example.com web.example.com"]);
+
+        // The records are written at other positions, so pointers must be rewritten
+        let reparsed = Datagram::unserialize(&datagram.serialize()).unwrap();
+        assert_eq!(names(&reparsed), expected);
+    }
+
+    #[test]
+    fn malformed_unserialize_test() {
+        let datagram_bytes = [0x44, 0x44, 0b00000001];
+        assert_eq!(Datagram::unserialize(&datagram_bytes).err(), Some(ParseError::Truncated));
+
+        // Two questions announced, only one present
+        let datagram_bytes = [
+            0x44, 0x44, 0b00000001, 0b00000000, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            3, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+        ];
+        assert_eq!(Datagram::unserialize(&datagram_bytes).err(), Some(ParseError::BadCount));
+
+        // Question cut in the middle of its type
+        let datagram_bytes = [
+            0x44, 0x44, 0b00000001, 0b00000000, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            3, b'c', b'o', b'm', 0x00, 0x00,
+        ];
+        assert_eq!(Datagram::unserialize(&datagram_bytes).err(), Some(ParseError::Truncated));
+    }
+
+    #[test]
+    fn hostile_pointer_unserialize_test() {
+        // Question name pointing at itself
+        let datagram_bytes = [
+            0x44, 0x44, 0b00000001, 0b00000000, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01,
+        ];
+        assert_eq!(Datagram::unserialize(&datagram_bytes).err(), Some(ParseError::PointerLoop));
+
+        // Answer name pointing forward, into its own RDATA
+        let datagram_bytes = [
+            0x44, 0x44, 0b10000000, 0b00000000, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            3, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+            0xC0, 0x1F, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x68, 0x00, 0x04, 0xC0, 0x15, 0, 1,
+        ];
+        assert_eq!(Datagram::unserialize(&datagram_bytes).err(), Some(ParseError::PointerLoop));
+    }
 }
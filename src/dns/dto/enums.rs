@@ -18,11 +18,66 @@ pub enum TYPE {
     /// IPv4 IP request
     A = 1,
 
+    /// # NS type
+    ///
+    /// Authoritative name server
+    NS = 2,
+
+    /// # CNAME type
+    ///
+    /// Canonical name of an alias
+    CNAME = 5,
+
+    /// # SOA type
+    ///
+    /// Start of a zone of authority
+    SOA = 6,
+
+    /// # PTR type
+    ///
+    /// Domain name pointer, mostly used for reverse lookups
+    PTR = 12,
+
+    /// # MX type
+    ///
+    /// Mail exchange
+    MX = 15,
+
+    /// # TXT type
+    ///
+    /// Text strings
+    TXT = 16,
+
     /// # AAAA type
     ///
     /// IPv6 IP request
     AAAA = 28,
 
+    /// # SRV type
+    ///
+    /// Location of a service (RFC 2782)
+    SRV = 33,
+
+    /// # OPT type
+    ///
+    /// EDNS(0) pseudo-record, only found in the additional section (RFC 6891)
+    OPT = 41,
+
+    /// # SVCB type
+    ///
+    /// General purpose service binding (RFC 9460)
+    SVCB = 64,
+
+    /// # HTTPS type
+    ///
+    /// Service binding for HTTPS (RFC 9460)
+    HTTPS = 65,
+
+    /// # CAA type
+    ///
+    /// Certification authority authorization (RFC 8659)
+    CAA = 257,
+
     /// # Not yet implemented
     ///
     /// Used to keep compatibility with unimplemented types.
@@ -39,7 +94,18 @@ impl TYPE {
         let result: TYPE;
         match value {
             1 => result = TYPE::A,
+            2 => result = TYPE::NS,
+            5 => result = TYPE::CNAME,
+            6 => result = TYPE::SOA,
+            12 => result = TYPE::PTR,
+            15 => result = TYPE::MX,
+            16 => result = TYPE::TXT,
             28 => result = TYPE::AAAA,
+            33 => result = TYPE::SRV,
+            41 => result = TYPE::OPT,
+            64 => result = TYPE::SVCB,
+            65 => result = TYPE::HTTPS,
+            257 => result = TYPE::CAA,
 
             _ => result = TYPE::NotImplemented(value),
         }
@@ -66,7 +132,18 @@ impl fmt::Display for TYPE {
         let result: String;
         match self {
             TYPE::A => result = String::from("IPv4 host address"),
+            TYPE::NS => result = String::from("Name server"),
+            TYPE::CNAME => result = String::from("Canonical name"),
+            TYPE::SOA => result = String::from("Start of authority"),
+            TYPE::PTR => result = String::from("Domain name pointer"),
+            TYPE::MX => result = String::from("Mail exchange"),
+            TYPE::TXT => result = String::from("Text strings"),
             TYPE::AAAA => result = String::from("IPv6 host address"),
+            TYPE::SRV => result = String::from("Service location"),
+            TYPE::OPT => result = String::from("EDNS(0) option"),
+            TYPE::SVCB => result = String::from("Service binding"),
+            TYPE::HTTPS => result = String::from("HTTPS service binding"),
+            TYPE::CAA => result = String::from("Certification authority authorization"),
 
             TYPE::NotImplemented(value) => result = format!("Not implemented: {}", value),
         }
@@ -0,0 +1,46 @@
+use core::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// # Parse error
+///
+/// Reason why a datagram could not be read.
+///
+/// ## Behavior
+///
+/// A query that cannot be read is answered with a format error (FORMERR).
+pub enum ParseError {
+    /// The datagram ends in the middle of an element.
+    Truncated,
+
+    /// A label is too long, is not valid UTF-8 or is not a valid hostname label.
+    BadLabel,
+
+    /// A compression pointer does not point to a prior occurrence of a name,
+    /// or too many pointers are followed.
+    PointerLoop,
+
+    /// A name is longer than 255 octets.
+    NameTooLong,
+
+    /// The RDATA of a record does not match its type or its length.
+    BadRData,
+
+    /// The header announces more elements than the datagram contains.
+    BadCount,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = match self {
+            ParseError::Truncated => "Truncated datagram",
+            ParseError::BadLabel => "Invalid label",
+            ParseError::PointerLoop => "Invalid compression pointer",
+            ParseError::NameTooLong => "Name longer than 255 octets",
+            ParseError::BadRData => "Invalid RDATA",
+            ParseError::BadCount => "Section count does not match the content",
+        };
+        write!(f, "{result}")
+    }
+}
+
+impl std::error::Error for ParseError {}
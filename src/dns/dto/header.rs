@@ -1,6 +1,8 @@
 use core::fmt;
 use rand::Rng;
 
+use super::error::ParseError;
+
 #[derive(Clone)]
 
 pub struct Header {
@@ -133,15 +135,15 @@ impl Header {
         self.content[2] & 0x40 == 0x40
     }
 
-    pub fn unserialize(stream: &[u8]) -> Self {
+    pub fn unserialize(stream: &[u8]) -> Result<Self, ParseError> {
         let mut content: [u8; 12] = [0; 12];
-        content.copy_from_slice(&stream[0..12]);
-        Self {
+        content.copy_from_slice(stream.get(0..12).ok_or(ParseError::Truncated)?);
+        Ok(Self {
             content
-        }
+        })
     }
 
-    pub fn serialize(self) -> [u8; 12] {
+    pub fn serialize(&self) -> [u8; 12] {
         self.content
     }
 
@@ -282,7 +284,7 @@ impl fmt::Display for OPCODE {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 #[repr(u8)]
 pub enum RCODE {
     /// No error during execution
@@ -302,7 +304,7 @@ pub enum RCODE {
 }
 
 impl RCODE {
-    fn from_u8(value: u8) -> RCODE {
+    pub fn from_u8(value: u8) -> RCODE {
         let result: RCODE;
         match value {
             0 => result = RCODE::NoError,
@@ -317,7 +319,7 @@ impl RCODE {
         return result;
     }
 
-    fn to_u8(&self) -> u8 {
+    pub fn to_u8(&self) -> u8 {
         let result: u8;
         match self {
             RCODE::NotImplemented(value) => result = *value,
@@ -354,13 +356,19 @@ mod tests {
         let bytes = [
             0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
         ];
-        let header = Header::unserialize(&bytes);
+        let header = Header::unserialize(&bytes).unwrap();
         assert_eq!(bytes, header.serialize());
 
         let bytes = [
             0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
         ];
-        let header = Header::unserialize(&bytes);
+        let header = Header::unserialize(&bytes).unwrap();
         assert_eq!(bytes, header.serialize());
     }
+
+    #[test]
+    fn truncated_unserialize_test() {
+        let bytes = [0xff, 0xff, 0xff];
+        assert_eq!(Header::unserialize(&bytes).err(), Some(ParseError::Truncated));
+    }
 }
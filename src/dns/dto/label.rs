@@ -1,34 +1,47 @@
 use core::fmt;
-use std::hash::Hash;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 
-#[derive(Clone, PartialEq, Hash, Eq, Ord, PartialOrd, Default)]
+use super::error::ParseError;
+
+#[derive(Clone, Default)]
+/// # Label
+///
+/// Labels are compared without regard to ASCII case (RFC 4343), but keep
+/// their original case, so that names are echoed as they were received.
 pub struct Label {
-    pub value: String,
+    pub value: Vec<u8>,
 }
 
 impl Label {
-    pub fn unserialize(stream: &[u8], mut offset: usize) -> Result<(Label, usize), ()> {
-        let length = stream[offset] as usize;
+    pub fn unserialize(stream: &[u8], mut offset: usize) -> Result<(Label, usize), ParseError> {
+        let length = *stream.get(offset).ok_or(ParseError::Truncated)? as usize;
+        // Lengths of 64 and more are reserved for other label types.
+        if length >= 64 {
+            return Err(ParseError::BadLabel);
+        }
         offset += 1;
-        
+
+        let bytes = stream.get(offset..offset + length).ok_or(ParseError::Truncated)?;
+        // Labels received are not held to hostname rules, as names such as
+        // _sip._tcp.example.com or 1.0.0.127.in-addr.arpa are valid in DNS,
+        // and any octet may appear in a label (RFC 2181, section 11).
         let label = Self {
-            value: String::from_utf8(stream[offset..offset + length].to_vec()).unwrap() 
+            value: bytes.to_vec(),
         };
-        if !label.is_valid() {
-            return Err(());
-        }
 
         Ok((label, offset + length))
     }
 
     pub fn serialize(&self, bytes: &mut Vec<u8>) {
         bytes.push(self.value.len() as u8);
-        bytes.extend_from_slice(self.value.as_bytes());
+        bytes.extend_from_slice(&self.value);
     }
 
+    /// Checks that the label follows hostname rules (RFC 1123).
     pub fn is_valid(&self) -> bool {
-        let bytes = self.value.as_bytes();
-        if self.value.len() >= 64 {
+        let bytes = &self.value;
+        if self.value.is_empty() || self.value.len() >= 64 {
             return false;
         }
         if !bytes[0].is_ascii_alphanumeric() {
@@ -49,8 +62,8 @@ impl Label {
         digits < bytes.len()
     }
 
-    pub fn as_str(&self) -> &str {
-        self.value.as_str()
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.value
     }
 }
 
@@ -65,14 +78,43 @@ impl From<&str> for Label {
     /// by remote servers, marking the request as if it has a format error.
     fn from(value: &str) -> Self {
         return Label {
-            value: value.to_string(),
+            value: value.as_bytes().to_vec(),
         };
     }
 }
 
+impl PartialEq for Label {
+    fn eq(&self, other: &Self) -> bool {
+        self.value.eq_ignore_ascii_case(&other.value)
+    }
+}
+
+impl Eq for Label {}
+
+impl Hash for Label {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.value.len());
+        self.value.iter().for_each(|byte| state.write_u8(byte.to_ascii_lowercase()));
+    }
+}
+
+impl Ord for Label {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lowercase = |label: &Self| label.value.iter().map(|byte| byte.to_ascii_lowercase()).collect::<Vec<u8>>();
+        lowercase(self).cmp(&lowercase(other))
+    }
+}
+
+impl PartialOrd for Label {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Octets that are not valid UTF-8 are shown as U+FFFD.
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.value)
+        write!(f, "{}", String::from_utf8_lossy(&self.value))
     }
 }
 
@@ -93,6 +135,44 @@ mod tests {
         assert_eq!(byte_read, bytes.len());
     }
 
+    #[test]
+    fn invalid_unserialize_test() {
+        let bytes = [4, b'w', b'w', b'w'];
+        assert_eq!(Label::unserialize(&bytes, 0).err(), Some(ParseError::Truncated));
+
+        let bytes = [0x41, b'w', b'w', b'w'];
+        assert_eq!(Label::unserialize(&bytes, 0).err(), Some(ParseError::BadLabel));
+    }
+
+    #[test]
+    fn unserialize_non_hostname_test() {
+        // Labels received are not checked against hostname rules, is_valid does it.
+        let bytes = [3, b'w', b' ', b'w'];
+        let (label, _) = Label::unserialize(&bytes, 0).unwrap();
+        assert_eq!(label.as_bytes(), b"w w");
+        assert!(!label.is_valid());
+        assert!(Label::from("www").is_valid());
+        assert!(!Label::from("w.w").is_valid());
+
+        // Nor required to be UTF-8
+        let bytes = [2, 0xC3, 0x28];
+        let (label, _) = Label::unserialize(&bytes, 0).unwrap();
+        assert_eq!(label.as_bytes(), [0xC3, 0x28]);
+        assert!(!label.is_valid());
+        let mut result = Vec::new();
+        label.serialize(&mut result);
+        assert_eq!(result, bytes);
+    }
+
+    #[test]
+    fn service_unserialize_test() {
+        let bytes = [4, b'_', b's', b'i', b'p', 3, b'1', b'2', b'7'];
+        let (label, byte_read) = Label::unserialize(&bytes, 0).unwrap();
+        assert_eq!(label.to_string(), "_sip");
+        let (label, _) = Label::unserialize(&bytes, byte_read).unwrap();
+        assert_eq!(label.to_string(), "127");
+    }
+
     #[test]
     fn basic_serialize_test() {
         let bytes = [3, b'w', b'w', b'w'];
@@ -100,4 +180,21 @@ mod tests {
         Label::from("www").serialize(&mut result);
         assert_eq!(result, bytes);
     }
+
+    #[test]
+    fn case_insensitive_test() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let hash = |label: &Label| {
+            let mut hasher = DefaultHasher::new();
+            label.hash(&mut hasher);
+            hasher.finish()
+        };
+        let label = Label::from("WwW");
+        assert!(label == Label::from("www"));
+        assert_eq!(hash(&label), hash(&Label::from("www")));
+        assert_eq!(label.cmp(&Label::from("WWW")), Ordering::Equal);
+        assert!(label != Label::from("ww"));
+        assert_eq!(label.as_bytes(), b"WwW");
+    }
 }
@@ -1,7 +1,10 @@
 pub mod datagram;
 pub mod enums;
+pub mod error;
 pub mod header;
 pub mod label;
 pub mod name;
+pub mod opt;
 pub mod question;
+pub mod rdata;
 pub mod resource_record;
@@ -5,6 +5,7 @@ use std::hash::{Hash, Hasher};
 use crate::dns::compression::{LabelTree, ReferencedLabel};
 use std::collections::VecDeque;
 
+use super::error::ParseError;
 use super::label::Label;
 
 
@@ -14,49 +15,68 @@ pub struct Name {
 }
 
 impl Name {
-    pub fn unserialize(stream: &[u8], offset: usize) -> Result<(Name, usize), ()> {
+    /// Maximum number of compression pointers followed while reading a name.
+    const MAX_POINTERS: usize = 64;
+
+    /// Maximum length of a name on the wire, including length octets
+    /// and the final null label (RFC 1035, section 3.1).
+    pub const MAX_LENGTH: usize = 255;
+
+    /// Reads a name, following compression pointers.
+    ///
+    /// A pointer must reference a position before the sequence of labels
+    /// it ends, so that reading always moves backwards and terminates.
+    ///
+    /// Returns the name and the position right after it in the stream.
+    pub fn unserialize(stream: &[u8], offset: usize) -> Result<(Name, usize), ParseError> {
         let mut i = offset;
+        let mut sequence_begin = offset;
+        let mut end: Option<usize> = None;
+        let mut pointers: usize = 0;
+        let mut length: usize = 1;
         let mut labels: VecDeque<Label> = VecDeque::new();
-        while stream[i] > 0 {
+
+        while *stream.get(i).ok_or(ParseError::Truncated)? > 0 {
             let sequence_info = stream[i] as usize;
 
             // Check if there is a reference
             if sequence_info & 0xC0 == 0xC0 {
-                let referenced_address = (sequence_info & 0b00111111) << 8 | stream[i + 1] as usize;
-                match Name::unserialize(stream, referenced_address) {
-                    Ok((mut name, _)) => {
-                        labels.append(&mut name.labels);
-                        return Ok((Name { labels }, i + 2));
-                    }
-                    Err(()) => return Err(()),
+                let pointer_end = *stream.get(i + 1).ok_or(ParseError::Truncated)? as usize;
+                let referenced_address = (sequence_info & 0b00111111) << 8 | pointer_end;
+                pointers += 1;
+                if referenced_address >= sequence_begin || pointers > Self::MAX_POINTERS {
+                    return Err(ParseError::PointerLoop);
                 }
+                end.get_or_insert(i + 2);
+                i = referenced_address;
+                sequence_begin = referenced_address;
             } else {
-                match Label::unserialize(stream, i) {
-                    Ok((label, read_head)) => {
-                        labels.push_back(label);
-                        i = read_head;
-                    }
-                    Err(_) => return Err(()),
+                let (label, read_head) = Label::unserialize(stream, i)?;
+                length += read_head - i;
+                if length > Self::MAX_LENGTH {
+                    return Err(ParseError::NameTooLong);
                 }
+                labels.push_back(label);
+                i = read_head;
             }
         }
         labels.make_contiguous();
 
-        return Ok((Name { labels }, i + 1));
+        return Ok((Name { labels }, end.unwrap_or(i + 1)));
     }
 
-    pub fn serialize(mut self, bytes: &mut Vec<u8>, tree: &mut LabelTree) {
-        let reference = tree.find_best_reference(&self);
+    pub fn serialize(&self, bytes: &mut Vec<u8>, tree: &mut LabelTree) {
+        let reference = tree.find_best_reference(self);
         let mut new_references: Vec<ReferencedLabel> = Vec::with_capacity(self.labels.len());
 
-        while self.labels.len() > 0 {
-            let label = self.labels.pop_front().unwrap();
-            if self.labels.len() >= reference.index {
+        for (i, label) in self.labels.iter().enumerate() {
+            // Labels after this one are replaced by the reference
+            if self.labels.len() - i > reference.index {
                 let pointer = bytes.len();
                 label.serialize(bytes);
-                new_references.push(ReferencedLabel::new(label, pointer as u16));
+                new_references.push(ReferencedLabel::new(label.clone(), pointer as u16));
             } else {
-                new_references.push(ReferencedLabel::new(label, 0));
+                new_references.push(ReferencedLabel::new(label.clone(), 0));
             }
         }
 
@@ -70,19 +90,44 @@ impl Name {
         tree.insert(new_references);
     }
 
+    /// Writes the name without any compression pointer.
+    pub fn serialize_uncompressed(&self, bytes: &mut Vec<u8>) {
+        self.labels.iter().for_each(|label| label.serialize(bytes));
+        bytes.push(0);
+    }
+
+    /// The root name, written as a single null byte.
+    pub fn root() -> Self {
+        Name {
+            labels: VecDeque::new(),
+        }
+    }
+
+    /// Copy of the name with ASCII letters in lowercase.
+    pub fn to_lowercase(&self) -> Self {
+        Name {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| Label { value: label.value.to_ascii_lowercase() })
+                .collect(),
+        }
+    }
+
     pub fn get_string(&self) -> String {
         let mut s: Vec<u8> = Vec::with_capacity(16);
         if self.labels.len() >= 1 {
-            s = self.labels[0].value.clone().into();
+            s = self.labels[0].value.clone();
             if self.labels.len() > 1 {
                 for label in self.labels.as_slices().0[1..].iter() {
                     s.push(b'.');
-                    let _ = s.write_all(label.value.as_bytes());
+                    let _ = s.write_all(&label.value);
                 }
             }
         } 
 
-        String::from_utf8(s).unwrap()
+        // Octets that are not valid UTF-8 are shown as U+FFFD.
+        String::from_utf8_lossy(&s).into_owned()
     }
 
     pub fn as_labels(&self) -> &[Label]{
@@ -100,9 +145,11 @@ impl fmt::Display for Name {
     }
 }
 
+/// Names are compared without regard to ASCII case, like their labels.
 impl Hash for Name {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.labels.as_slices().0[0..self.labels.len() - 1].iter().for_each(|l| l.hash(state));
+        state.write_usize(self.labels.len());
+        self.labels.iter().for_each(|l| l.hash(state));
     }
 }
 
@@ -147,24 +194,116 @@ mod tests {
     #[test]
     fn test_referenced_unserialize() {
         let value: Vec<u8> = vec![
-            0xc0, 0x07, 4, 3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c',
-            b'o', b'm', 0,
+            3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0xc0, 0x04,
         ];
-        let offset = 2;
-        let (name, i) = Name::unserialize(&value, 0).unwrap();
+        let offset = value.len();
+        let (name, i) = Name::unserialize(&value, 16).unwrap();
         assert_eq!(name.to_string(), "google.com");
         assert_eq!(i as usize, offset);
 
         let value: Vec<u8> = vec![
-            4, b't', b'e', b's', b't', 0xc0, 0x0c, 4, 3, b'w', b'w', b'w', 6, b'g', b'o', b'o',
-            b'g', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            4, b't', b'e', b's', b't', 0xc0, 0x04, 0xFF,
         ];
-        let offset = 7;
-        let (name, i) = Name::unserialize(&value, 0).unwrap();
+        let offset = value.len() - 1;
+        let (name, i) = Name::unserialize(&value, 16).unwrap();
         assert_eq!(name.to_string(), "test.google.com");
         assert_eq!(i as usize, offset);
     }
 
+    #[test]
+    fn test_chained_references_unserialize() {
+        let value: Vec<u8> = vec![
+            3, b'c', b'o', b'm', 0,
+            6, b'g', b'o', b'o', b'g', b'l', b'e', 0xc0, 0x00,
+            3, b'w', b'w', b'w', 0xc0, 0x05,
+        ];
+        let (name, i) = Name::unserialize(&value, 14).unwrap();
+        assert_eq!(name.to_string(), "www.google.com");
+        assert_eq!(i, value.len());
+    }
+
+    #[test]
+    fn test_invalid_unserialize() {
+        let value: Vec<u8> = vec![3, b'w', b'w', b'w', 6, b'g', b'o'];
+        assert_eq!(Name::unserialize(&value, 0).err(), Some(ParseError::Truncated));
+
+        let value: Vec<u8> = vec![3, b'w', b'w', b'w', 0xc0];
+        assert_eq!(Name::unserialize(&value, 0).err(), Some(ParseError::Truncated));
+    }
+
+    #[test]
+    fn test_self_pointer_unserialize() {
+        let value: Vec<u8> = vec![0xc0, 0x00];
+        assert_eq!(Name::unserialize(&value, 0).err(), Some(ParseError::PointerLoop));
+
+        let value: Vec<u8> = vec![0, 0, 3, b'w', b'w', b'w', 0xc0, 0x02];
+        assert_eq!(Name::unserialize(&value, 2).err(), Some(ParseError::PointerLoop));
+    }
+
+    #[test]
+    fn test_pointer_loop_unserialize() {
+        // Two names pointing at each other.
+        let value: Vec<u8> = vec![
+            3, b'w', b'w', b'w', 0xc0, 0x06,
+            3, b'c', b'o', b'm', 0xc0, 0x00,
+        ];
+        assert_eq!(Name::unserialize(&value, 0).err(), Some(ParseError::PointerLoop));
+        assert_eq!(Name::unserialize(&value, 6).err(), Some(ParseError::PointerLoop));
+
+        // A pointer jumping back into the labels it just read.
+        let value: Vec<u8> = vec![0, 3, b'w', b'w', b'w', 3, b'c', b'o', b'm', 0xc0, 0x05];
+        assert_eq!(Name::unserialize(&value, 1).err(), Some(ParseError::PointerLoop));
+    }
+
+    #[test]
+    fn test_pointer_outside_unserialize() {
+        let value: Vec<u8> = vec![0; 4];
+        let mut value = [value, vec![0xff, 0xff]].concat();
+        assert_eq!(Name::unserialize(&value, 4).err(), Some(ParseError::PointerLoop));
+
+        value.truncate(5);
+        assert_eq!(Name::unserialize(&value, 4).err(), Some(ParseError::Truncated));
+    }
+
+    #[test]
+    fn test_long_name_unserialize() {
+        // 4 labels of 63 octets: 4 * 64 + 1 = 257 octets.
+        let mut value: Vec<u8> = Vec::new();
+        for _ in 0..4 {
+            value.push(63);
+            value.extend_from_slice(&[b'a'; 63]);
+        }
+        value.push(0);
+        assert_eq!(Name::unserialize(&value, 0).err(), Some(ParseError::NameTooLong));
+
+        // The limit also applies to names assembled from pointers.
+        let mut value: Vec<u8> = Vec::new();
+        for _ in 0..3 {
+            value.push(63);
+            value.extend_from_slice(&[b'a'; 63]);
+        }
+        value.push(0);
+        let referenced = value.len();
+        value.push(63);
+        value.extend_from_slice(&[b'b'; 63]);
+        value.extend_from_slice(&[0xc0, 0x00]);
+        assert_eq!(Name::unserialize(&value, referenced).err(), Some(ParseError::NameTooLong));
+
+        // 3 labels of 63 octets and one of 61: exactly 255 octets.
+        let mut value: Vec<u8> = Vec::new();
+        for _ in 0..3 {
+            value.push(63);
+            value.extend_from_slice(&[b'a'; 63]);
+        }
+        value.push(61);
+        value.extend_from_slice(&[b'a'; 61]);
+        value.push(0);
+        assert_eq!(value.len(), Name::MAX_LENGTH);
+        assert!(Name::unserialize(&value, 0).is_ok());
+    }
+
     #[test]
     fn test_empty_unserialize() {
         let value: Vec<u8> = vec![0, 0, 0, 0];
@@ -185,4 +324,18 @@ mod tests {
         name.serialize(&mut bytes, &mut lt);
         assert_eq!(bytes, expected);
     }
+
+    #[test]
+    fn test_case_insensitive() {
+        use std::collections::HashSet;
+
+        let name = Name::from("WWW.Example.com");
+        assert!(name == Name::from("www.example.com"));
+        assert!(name != Name::from("www.example.org"));
+        assert_eq!(name.to_string(), "WWW.Example.com");
+
+        let names: HashSet<Name> = HashSet::from([name, Name::root()]);
+        assert!(names.contains(&Name::from("www.EXAMPLE.com")));
+        assert!(names.contains(&Name::root()));
+    }
 }
@@ -0,0 +1,153 @@
+use core::fmt;
+
+use super::enums::{CLASS, TYPE};
+use super::name::Name;
+use super::rdata::{EdnsOption, RData};
+use super::resource_record::ResourceRecord;
+
+#[derive(Clone, PartialEq, Debug)]
+/// # OPT pseudo-record
+///
+/// Carries the EDNS(0) parameters of a datagram, specified in RFC 6891.
+///
+/// ## Structure
+/// NAME: root
+///
+/// TYPE: OPT (41)
+///
+/// CLASS: UDP payload size of the sender
+///
+/// TTL: Extended RCODE (8 bits), version (8 bits), DO (1 bit), Z (15 bits)
+///
+/// RDATA: Options, {code, length, data}*
+pub struct Opt {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    flags: u16,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Opt {
+    /// Payload size that every DNS implementation must accept.
+    pub const MIN_PAYLOAD_SIZE: u16 = 512;
+
+    /// Extended RCODE returned when the requested EDNS version is not supported.
+    pub const BADVERS: u16 = 16;
+
+    const DNSSEC_OK: u16 = 0x8000;
+
+    pub fn new(udp_payload_size: u16) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            flags: 0,
+            options: Vec::new(),
+        }
+    }
+
+    /// Reads the EDNS(0) parameters from an OPT record.
+    ///
+    /// Returns `None` if the record is not of type OPT.
+    pub fn from_record(rr: &ResourceRecord) -> Option<Self> {
+        if rr.get_type() != TYPE::OPT {
+            return None;
+        }
+        let ttl = rr.get_ttl();
+        let options = match &rr.rdata {
+            RData::OPT(options) => options.clone(),
+            _ => Vec::new(),
+        };
+        Some(Self {
+            udp_payload_size: rr.get_class().to_u16(),
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            flags: ttl as u16,
+            options,
+        })
+    }
+
+    pub fn to_record(&self) -> ResourceRecord {
+        let ttl = (self.extended_rcode as u32) << 24
+            | (self.version as u32) << 16
+            | self.flags as u32;
+        ResourceRecord::new(
+            Name::root(),
+            TYPE::OPT,
+            CLASS::from_u16(self.udp_payload_size),
+            ttl,
+            RData::OPT(self.options.clone()),
+        )
+    }
+
+    /// Payload size usable with the sender.
+    ///
+    /// Values lower than 512 must be treated as 512.
+    pub fn get_payload_size(&self) -> u16 {
+        self.udp_payload_size.max(Self::MIN_PAYLOAD_SIZE)
+    }
+
+    pub fn is_dnssec_ok(&self) -> bool {
+        self.flags & Self::DNSSEC_OK == Self::DNSSEC_OK
+    }
+
+    pub fn set_dnssec_ok(&mut self, dnssec_ok: bool) {
+        self.flags &= !Self::DNSSEC_OK;
+        if dnssec_ok {
+            self.flags |= Self::DNSSEC_OK;
+        }
+    }
+}
+
+impl fmt::Display for Opt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "UDP PAYLOAD SIZE: {}\nEXTENDED RCODE: {}\nVERSION: {}\nDO: {}\nOPTIONS: {:?}\n",
+            self.udp_payload_size, self.extended_rcode, self.version, self.is_dnssec_ok(), self.options
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::compression::LabelTree;
+
+    #[test]
+    fn unserialize_test() {
+        let opt_bytes = [
+            0, 0x00, 0x29, 0x04, 0xD0, 0x01, 0x00, 0x80, 0x00, 0x00, 0x04, 0x00, 0x0A, 0x00, 0x00,
+        ];
+        let (rr, _) = ResourceRecord::unserialize(&opt_bytes, 0).unwrap();
+        let opt = Opt::from_record(&rr).unwrap();
+        assert_eq!(opt.udp_payload_size, 1232);
+        assert_eq!(opt.extended_rcode, 1);
+        assert_eq!(opt.version, 0);
+        assert!(opt.is_dnssec_ok());
+        assert_eq!(opt.options, [EdnsOption { code: 10, data: vec![] }]);
+    }
+
+    #[test]
+    fn serialize_test() {
+        let mut opt = Opt::new(4096);
+        opt.set_dnssec_ok(true);
+        let mut bytes = Vec::new();
+        opt.to_record().serialize(&mut bytes, &mut LabelTree::default());
+        assert_eq!(bytes, [0, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn payload_size_test() {
+        assert_eq!(Opt::new(0).get_payload_size(), 512);
+        assert_eq!(Opt::new(1232).get_payload_size(), 1232);
+    }
+
+    #[test]
+    fn not_opt_test() {
+        let rr_bytes = [0, 0x00, 0x01, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x04, 127, 0, 0, 1];
+        let (rr, _) = ResourceRecord::unserialize(&rr_bytes, 0).unwrap();
+        assert!(Opt::from_record(&rr).is_none());
+    }
+}
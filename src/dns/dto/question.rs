@@ -1,6 +1,7 @@
 use crate::dns::compression::LabelTree;
 
 use super::enums::{CLASS, TYPE};
+use super::error::ParseError;
 use super::name::Name;
 use core::fmt;
 
@@ -11,15 +12,15 @@ pub struct Question {
 }
 
 impl Question {
-    pub fn unserialize(stream: &[u8], offset: u16) -> (Self, u16) {
-        let (qname, i) = Name::unserialize(stream, offset as usize).unwrap();
+    pub fn unserialize(stream: &[u8], offset: u16) -> Result<(Self, u16), ParseError> {
+        let (qname, i) = Name::unserialize(stream, offset as usize)?;
         let mut content: [u8; 4] = [0; 4];
-        content.copy_from_slice(&stream[i..i + 4]);
+        content.copy_from_slice(stream.get(i..i + 4).ok_or(ParseError::Truncated)?);
 
-        (Self {qname, content}, i as u16 + 4)
+        Ok((Self {qname, content}, i as u16 + 4))
     }
 
-    pub fn serialize(self, bytes: &mut Vec<u8>, lt: &mut LabelTree) {
+    pub fn serialize(&self, bytes: &mut Vec<u8>, lt: &mut LabelTree) {
         self.qname.serialize(bytes, lt);
         bytes.extend_from_slice(&self.content);
     }
@@ -53,7 +54,7 @@ mod tests {
             3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0,
             0x44, 0x22, 0x01, 0x10,
         ];
-        let (question, offset) = Question::unserialize(&question_bytes, 0);
+        let (question, offset) = Question::unserialize(&question_bytes, 0).unwrap();
         assert_eq!(offset as usize, question_bytes.len());
         assert_eq!(question.qname.to_string(), "www.google.com");
         assert_eq!(question.get_type().to_u16(), 0x4422);
@@ -63,10 +64,17 @@ mod tests {
             0, 0, 0, 0, 3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o',
             b'm', 0, 0x44, 0x22, 0x01, 0x10,
         ];
-        let (question, offset) = Question::unserialize(&question_bytes, 4);
+        let (question, offset) = Question::unserialize(&question_bytes, 4).unwrap();
         assert_eq!(offset as usize, question_bytes.len());
         assert_eq!(question.qname.to_string(), "www.google.com");
         assert_eq!(question.get_type().to_u16(), 0x4422);
         assert_eq!(question.get_class().to_u16(), 0x0110);
     }
+
+    #[test]
+    fn truncated_unserialize_test() {
+        let question_bytes = [3, b'w', b'w', b'w', 0, 0x00, 0x01, 0x00];
+        let result = Question::unserialize(&question_bytes, 0);
+        assert_eq!(result.err(), Some(ParseError::Truncated));
+    }
 }
@@ -0,0 +1,619 @@
+use core::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dns::compression::LabelTree;
+
+use super::enums::TYPE;
+use super::error::ParseError;
+use super::name::Name;
+
+#[derive(Clone, PartialEq)]
+/// # RDATA
+///
+/// Content of a resource record, decoded according to its type.
+///
+/// ## Names
+///
+/// Names are decompressed when read. When written, only the types defined
+/// in RFC 1035 are compressed, as other implementations may not expect
+/// compression in newer types (RFC 3597, section 4).
+pub enum RData {
+    A(Ipv4Addr),
+    NS(Name),
+    CNAME(Name),
+    SOA {
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR(Name),
+    MX {
+        preference: u16,
+        exchange: Name,
+    },
+    TXT(Vec<Vec<u8>>),
+    AAAA(Ipv6Addr),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Name,
+    },
+    OPT(Vec<EdnsOption>),
+    SVCB(ServiceBinding),
+    HTTPS(ServiceBinding),
+    CAA {
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+    },
+
+    /// Content of other types holding names, which the sender may have
+    /// compressed (RFC 3597, section 4). Names are kept decoded so that
+    /// they stay valid wherever the record is written.
+    Fields {
+        rtype: u16,
+        fields: Vec<Field>,
+    },
+
+    /// Content of types that are not decoded, kept as is.
+    Unknown(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq)]
+/// Part of the content of a record with embedded names.
+pub enum Field {
+    Bytes(Vec<u8>),
+    Name(Name),
+}
+
+/// Shape of the content of a record with embedded names.
+#[derive(Clone, Copy)]
+enum Layout {
+    Name,
+    Fixed(usize),
+    CharacterString,
+    Rest,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+/// Option of an OPT record, {code, length, data}.
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq)]
+/// Content of SVCB and HTTPS records (RFC 9460).
+pub struct ServiceBinding {
+    pub priority: u16,
+    pub target: Name,
+
+    /// Service parameters, {key, value}, in increasing key order.
+    pub params: Vec<(u16, Vec<u8>)>,
+}
+
+impl RData {
+    /// Reads the RDATA of a record of type `rtype`, which starts at `offset`
+    /// and spans `length` bytes.
+    ///
+    /// The whole datagram is needed to follow compression pointers.
+    pub fn unserialize(rtype: &TYPE, stream: &[u8], offset: usize, length: usize) -> Result<RData, ParseError> {
+        if offset + length > stream.len() {
+            return Err(ParseError::Truncated);
+        }
+        let mut reader = Reader {
+            stream,
+            position: offset,
+            end: offset + length,
+        };
+
+        match Self::decode(rtype, &mut reader) {
+            Err(ParseError::BadRData) if Self::is_opaque(rtype) => {
+                Ok(RData::Unknown(stream[offset..offset + length].to_vec()))
+            }
+            result => result,
+        }
+    }
+
+    /// Decodes the whole content read by `reader`.
+    fn decode(rtype: &TYPE, reader: &mut Reader) -> Result<RData, ParseError> {
+        let rdata = match rtype {
+            TYPE::A => {
+                let octets: [u8; 4] = reader.bytes(4)?.try_into().unwrap();
+                RData::A(Ipv4Addr::from(octets))
+            }
+            TYPE::NS => RData::NS(reader.name()?),
+            TYPE::CNAME => RData::CNAME(reader.name()?),
+            TYPE::SOA => RData::SOA {
+                mname: reader.name()?,
+                rname: reader.name()?,
+                serial: reader.u32()?,
+                refresh: reader.u32()?,
+                retry: reader.u32()?,
+                expire: reader.u32()?,
+                minimum: reader.u32()?,
+            },
+            TYPE::PTR => RData::PTR(reader.name()?),
+            TYPE::MX => RData::MX {
+                preference: reader.u16()?,
+                exchange: reader.name()?,
+            },
+            TYPE::TXT => {
+                let mut strings: Vec<Vec<u8>> = Vec::new();
+                while reader.remaining() > 0 {
+                    let length = reader.u8()? as usize;
+                    strings.push(reader.bytes(length)?.to_vec());
+                }
+                RData::TXT(strings)
+            }
+            TYPE::AAAA => {
+                let octets: [u8; 16] = reader.bytes(16)?.try_into().unwrap();
+                RData::AAAA(Ipv6Addr::from(octets))
+            }
+            TYPE::SRV => RData::SRV {
+                priority: reader.u16()?,
+                weight: reader.u16()?,
+                port: reader.u16()?,
+                target: reader.name()?,
+            },
+            TYPE::OPT => {
+                let mut options: Vec<EdnsOption> = Vec::new();
+                while reader.remaining() > 0 {
+                    let code = reader.u16()?;
+                    let length = reader.u16()? as usize;
+                    options.push(EdnsOption {
+                        code,
+                        data: reader.bytes(length)?.to_vec(),
+                    });
+                }
+                RData::OPT(options)
+            }
+            TYPE::SVCB => RData::SVCB(reader.service_binding()?),
+            TYPE::HTTPS => RData::HTTPS(reader.service_binding()?),
+            TYPE::CAA => {
+                let flags = reader.u8()?;
+                let tag_length = reader.u8()? as usize;
+                let tag = String::from_utf8(reader.bytes(tag_length)?.to_vec())
+                    .map_err(|_| ParseError::BadRData)?;
+                let value = reader.bytes(reader.remaining())?.to_vec();
+                RData::CAA { flags, tag, value }
+            }
+            TYPE::NotImplemented(rtype) => match Self::get_layout(*rtype) {
+                Some(layout) => RData::Fields {
+                    rtype: *rtype,
+                    fields: reader.fields(layout)?,
+                },
+                None => RData::Unknown(reader.bytes(reader.remaining())?.to_vec()),
+            },
+        };
+
+        if reader.remaining() > 0 {
+            return Err(ParseError::BadRData);
+        }
+        Ok(rdata)
+    }
+
+    /// Types without names, whose content is kept as is when it cannot be
+    /// decoded, so that the record is still passed on to the client. OPT
+    /// records are read by this server, and must be well-formed.
+    fn is_opaque(rtype: &TYPE) -> bool {
+        matches!(rtype, TYPE::A | TYPE::AAAA | TYPE::TXT | TYPE::CAA)
+    }
+
+    /// Layout of the types that may hold compressed names (RFC 3597, section 4).
+    fn get_layout(rtype: u16) -> Option<&'static [Layout]> {
+        match rtype {
+            // MD, MF, MB, MG, MR, DNAME
+            3 | 4 | 7 | 8 | 9 | 39 => Some(&[Layout::Name]),
+            // MINFO, RP
+            14 | 17 => Some(&[Layout::Name, Layout::Name]),
+            // AFSDB, RT
+            18 | 21 => Some(&[Layout::Fixed(2), Layout::Name]),
+            // SIG
+            24 => Some(&[Layout::Fixed(18), Layout::Name, Layout::Rest]),
+            // PX
+            26 => Some(&[Layout::Fixed(2), Layout::Name, Layout::Name]),
+            // NXT
+            30 => Some(&[Layout::Name, Layout::Rest]),
+            // NAPTR
+            35 => Some(&[
+                Layout::Fixed(4),
+                Layout::CharacterString,
+                Layout::CharacterString,
+                Layout::CharacterString,
+                Layout::Name,
+            ]),
+            _ => None,
+        }
+    }
+
+    /// Only the types of RFC 1035 may be compressed when written.
+    fn is_compressible(rtype: u16) -> bool {
+        matches!(rtype, 3 | 4 | 7 | 8 | 9 | 14)
+    }
+
+    /// Writes the RDATA, without its length.
+    pub fn serialize(&self, bytes: &mut Vec<u8>, lt: &mut LabelTree) {
+        match self {
+            RData::A(address) => bytes.extend_from_slice(&address.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => {
+                name.serialize(bytes, lt);
+            }
+            RData::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+                mname.serialize(bytes, lt);
+                rname.serialize(bytes, lt);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            RData::MX { preference, exchange } => {
+                bytes.extend_from_slice(&preference.to_be_bytes());
+                exchange.serialize(bytes, lt);
+            }
+            RData::TXT(strings) => {
+                for string in strings {
+                    bytes.push(string.len() as u8);
+                    bytes.extend_from_slice(string);
+                }
+            }
+            RData::AAAA(address) => bytes.extend_from_slice(&address.octets()),
+            RData::SRV { priority, weight, port, target } => {
+                bytes.extend_from_slice(&priority.to_be_bytes());
+                bytes.extend_from_slice(&weight.to_be_bytes());
+                bytes.extend_from_slice(&port.to_be_bytes());
+                target.serialize_uncompressed(bytes);
+            }
+            RData::OPT(options) => {
+                for option in options {
+                    bytes.extend_from_slice(&option.code.to_be_bytes());
+                    bytes.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+                    bytes.extend_from_slice(&option.data);
+                }
+            }
+            RData::SVCB(binding) | RData::HTTPS(binding) => {
+                bytes.extend_from_slice(&binding.priority.to_be_bytes());
+                binding.target.serialize_uncompressed(bytes);
+                for (key, value) in binding.params.iter() {
+                    bytes.extend_from_slice(&key.to_be_bytes());
+                    bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                    bytes.extend_from_slice(value);
+                }
+            }
+            RData::CAA { flags, tag, value } => {
+                bytes.push(*flags);
+                bytes.push(tag.len() as u8);
+                bytes.extend_from_slice(tag.as_bytes());
+                bytes.extend_from_slice(value);
+            }
+            RData::Fields { rtype, fields } => {
+                for field in fields {
+                    match field {
+                        Field::Bytes(content) => bytes.extend_from_slice(content),
+                        Field::Name(name) if Self::is_compressible(*rtype) => {
+                            name.serialize(bytes, lt);
+                        }
+                        Field::Name(name) => name.serialize_uncompressed(bytes),
+                    }
+                }
+            }
+            RData::Unknown(content) => bytes.extend_from_slice(content),
+        }
+    }
+
+    /// Writes the RDATA on its own, without any compression.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self {
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => {
+                name.serialize_uncompressed(&mut bytes);
+            }
+            RData::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+                mname.serialize_uncompressed(&mut bytes);
+                rname.serialize_uncompressed(&mut bytes);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            RData::MX { preference, exchange } => {
+                bytes.extend_from_slice(&preference.to_be_bytes());
+                exchange.serialize_uncompressed(&mut bytes);
+            }
+            RData::Fields { fields, .. } => {
+                for field in fields {
+                    match field {
+                        Field::Bytes(content) => bytes.extend_from_slice(content),
+                        Field::Name(name) => name.serialize_uncompressed(&mut bytes),
+                    }
+                }
+            }
+            _ => self.serialize(&mut bytes, &mut LabelTree::default()),
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(address) => write!(f, "{address}"),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => write!(f, "{name}"),
+            RData::SOA { mname, rname, serial, refresh, retry, expire, minimum } => write!(
+                f,
+                "{mname} {rname} {serial} {refresh} {retry} {expire} {minimum}"
+            ),
+            RData::MX { preference, exchange } => write!(f, "{preference} {exchange}"),
+            RData::TXT(strings) => {
+                let strings = strings
+                    .iter()
+                    .map(|s| format!("{:?}", String::from_utf8_lossy(s)))
+                    .collect::<Vec<String>>();
+                write!(f, "{}", strings.join(" "))
+            }
+            RData::AAAA(address) => write!(f, "{address}"),
+            RData::SRV { priority, weight, port, target } => {
+                write!(f, "{priority} {weight} {port} {target}")
+            }
+            RData::OPT(options) => write!(f, "{:?}", options),
+            RData::SVCB(binding) | RData::HTTPS(binding) => {
+                write!(f, "{} {} {:?}", binding.priority, binding.target, binding.params)
+            }
+            RData::CAA { flags, tag, value } => {
+                write!(f, "{flags} {tag} {:?}", String::from_utf8_lossy(value))
+            }
+            RData::Fields { fields, .. } => {
+                let fields = fields
+                    .iter()
+                    .map(|field| match field {
+                        Field::Bytes(content) => format!("{:?}", content),
+                        Field::Name(name) => name.to_string(),
+                    })
+                    .collect::<Vec<String>>();
+                write!(f, "{}", fields.join(" "))
+            }
+            RData::Unknown(content) => write!(f, "{:?}", content),
+        }
+    }
+}
+
+impl fmt::Debug for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// Reads the fields of an RDATA, never going past its end.
+struct Reader<'a> {
+    stream: &'a [u8],
+    position: usize,
+    end: usize,
+}
+
+impl Reader<'_> {
+    fn remaining(&self) -> usize {
+        self.end - self.position
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&[u8], ParseError> {
+        if length > self.remaining() {
+            return Err(ParseError::BadRData);
+        }
+        let bytes = &self.stream[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn name(&mut self) -> Result<Name, ParseError> {
+        let (name, name_end) = Name::unserialize(&self.stream[..self.end], self.position)
+            .map_err(|e| match e {
+                ParseError::Truncated => ParseError::BadRData,
+                e => e,
+            })?;
+        self.position = name_end;
+        Ok(name)
+    }
+
+    fn fields(&mut self, layout: &[Layout]) -> Result<Vec<Field>, ParseError> {
+        let mut fields: Vec<Field> = Vec::with_capacity(layout.len());
+        for part in layout {
+            let field = match part {
+                Layout::Name => Field::Name(self.name()?),
+                Layout::Fixed(length) => Field::Bytes(self.bytes(*length)?.to_vec()),
+                Layout::CharacterString => {
+                    let length = self.stream.get(self.position).copied().ok_or(ParseError::BadRData)?;
+                    Field::Bytes(self.bytes(length as usize + 1)?.to_vec())
+                }
+                Layout::Rest => Field::Bytes(self.bytes(self.remaining())?.to_vec()),
+            };
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    fn service_binding(&mut self) -> Result<ServiceBinding, ParseError> {
+        let priority = self.u16()?;
+        let target = self.name()?;
+        let mut params: Vec<(u16, Vec<u8>)> = Vec::new();
+        while self.remaining() > 0 {
+            let key = self.u16()?;
+            let length = self.u16()? as usize;
+            params.push((key, self.bytes(length)?.to_vec()));
+        }
+        Ok(ServiceBinding {
+            priority,
+            target,
+            params,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(rtype: TYPE, rdata_bytes: &[u8]) -> RData {
+        let rdata = RData::unserialize(&rtype, rdata_bytes, 0, rdata_bytes.len()).unwrap();
+        assert_eq!(rdata.to_bytes(), rdata_bytes);
+        rdata
+    }
+
+    #[test]
+    fn address_test() {
+        let rdata = round_trip(TYPE::A, &[127, 0, 0, 1]);
+        assert!(rdata == RData::A(Ipv4Addr::new(127, 0, 0, 1)));
+
+        let rdata = round_trip(TYPE::AAAA, &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(rdata.to_string(), "2001:db8::1");
+
+        // Kept as is when malformed
+        let rdata = round_trip(TYPE::A, &[127, 0, 0]);
+        assert!(rdata == RData::Unknown(vec![127, 0, 0]));
+    }
+
+    #[test]
+    fn name_test() {
+        let bytes = [3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0];
+        assert_eq!(round_trip(TYPE::CNAME, &bytes).to_string(), "www.example.com");
+        assert_eq!(round_trip(TYPE::NS, &bytes).to_string(), "www.example.com");
+        assert_eq!(round_trip(TYPE::PTR, &bytes).to_string(), "www.example.com");
+    }
+
+    #[test]
+    fn compressed_name_test() {
+        // Owner name at 0, MX RDATA at 13 with its exchange compressed
+        let bytes = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0, 10, 4, b'm', b'a', b'i', b'l', 0xC0, 0x00,
+        ];
+        let rdata = RData::unserialize(&TYPE::MX, &bytes, 13, 9).unwrap();
+        assert_eq!(rdata.to_string(), "10 mail.example.com");
+
+        let mut bytes = Vec::new();
+        let mut lt = LabelTree::default();
+        Name::from("example.com").serialize(&mut bytes, &mut lt);
+        rdata.serialize(&mut bytes, &mut lt);
+        assert_eq!(bytes[13..], [0, 10, 4, b'm', b'a', b'i', b'l', 0xC0, 0x00]);
+    }
+
+    #[test]
+    fn soa_test() {
+        let mut bytes = vec![2, b'n', b's', 0, 4, b'r', b'o', b'o', b't', 0];
+        for value in [2024010101u32, 7200, 3600, 1209600, 300] {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        let rdata = round_trip(TYPE::SOA, &bytes);
+        match rdata {
+            RData::SOA { mname, minimum, .. } => {
+                assert_eq!(mname.to_string(), "ns");
+                assert_eq!(minimum, 300);
+            }
+            _ => panic!("Expected a SOA record"),
+        }
+    }
+
+    #[test]
+    fn txt_test() {
+        let rdata = round_trip(TYPE::TXT, &[5, b'h', b'e', b'l', b'l', b'o', 0, 2, b'h', b'i']);
+        assert!(rdata == RData::TXT(vec![b"hello".to_vec(), vec![], b"hi".to_vec()]));
+
+        let rdata = round_trip(TYPE::TXT, &[5, b'h', b'i']);
+        assert!(rdata == RData::Unknown(vec![5, b'h', b'i']));
+    }
+
+    #[test]
+    fn srv_test() {
+        let bytes = [0, 10, 0, 5, 0x13, 0xC4, 3, b's', b'i', b'p', 0];
+        let rdata = round_trip(TYPE::SRV, &bytes);
+        assert_eq!(rdata.to_string(), "10 5 5060 sip");
+    }
+
+    #[test]
+    fn opt_test() {
+        let rdata = round_trip(TYPE::OPT, &[0x00, 0x0A, 0x00, 0x02, 0xAB, 0xCD]);
+        assert!(rdata == RData::OPT(vec![EdnsOption { code: 10, data: vec![0xAB, 0xCD] }]));
+    }
+
+    #[test]
+    fn https_test() {
+        let bytes = [0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2'];
+        let rdata = round_trip(TYPE::HTTPS, &bytes);
+        match rdata {
+            RData::HTTPS(binding) => {
+                assert_eq!(binding.priority, 1);
+                assert_eq!(binding.target.to_string(), "");
+                assert_eq!(binding.params, [(1, vec![2, b'h', b'2'])]);
+            }
+            _ => panic!("Expected an HTTPS record"),
+        }
+    }
+
+    #[test]
+    fn caa_test() {
+        let mut bytes = vec![0, 5];
+        bytes.extend_from_slice(b"issue");
+        bytes.extend_from_slice(b"letsencrypt.org");
+        let rdata = round_trip(TYPE::CAA, &bytes);
+        assert_eq!(rdata.to_string(), "0 issue \"letsencrypt.org\"");
+
+        let rdata = round_trip(TYPE::CAA, &[0, 2, 0xC3, 0x28, b'x']);
+        assert!(rdata == RData::Unknown(vec![0, 2, 0xC3, 0x28, b'x']));
+    }
+
+    #[test]
+    fn unknown_test() {
+        let rdata = round_trip(TYPE::NotImplemented(99), &[1, 2, 3]);
+        assert!(rdata == RData::Unknown(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn embedded_name_test() {
+        // Owner name at 0, AFSDB RDATA at 13 with its hostname compressed
+        let bytes = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0, 1, 3, b'a', b'f', b's', 0xC0, 0x00,
+        ];
+        let rtype = TYPE::NotImplemented(18);
+        let rdata = RData::unserialize(&rtype, &bytes, 13, 8).unwrap();
+        assert_eq!(rdata.to_string(), "[0, 1] afs.example.com");
+
+        // Written elsewhere, the name is no longer compressed
+        let mut bytes = Vec::new();
+        rdata.serialize(&mut bytes, &mut LabelTree::default());
+        assert_eq!(bytes, [0, 1, 3, b'a', b'f', b's', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0]);
+    }
+
+    #[test]
+    fn naptr_test() {
+        let mut bytes = vec![0, 100, 0, 10, 1, b'u', 7];
+        bytes.extend_from_slice(b"E2U+sip");
+        bytes.extend_from_slice(&[0, 3, b's', b'i', b'p', 0]);
+        let rdata = round_trip(TYPE::NotImplemented(35), &bytes);
+        match rdata {
+            RData::Fields { fields, .. } => assert_eq!(fields.len(), 5),
+            _ => panic!("Expected a NAPTR record"),
+        }
+    }
+
+    #[test]
+    fn name_past_rdata_test() {
+        let bytes = [3, b'w', b'w', b'w', 0];
+        let result = RData::unserialize(&TYPE::CNAME, &bytes, 0, 3);
+        assert_eq!(result.err(), Some(ParseError::BadRData));
+    }
+}
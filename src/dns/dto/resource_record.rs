@@ -3,30 +3,58 @@ use core::fmt;
 use crate::dns::compression::LabelTree;
 
 use super::enums::{CLASS, TYPE};
+use super::error::ParseError;
 use super::name::Name;
-use super::question::Question;
+use super::rdata::RData;
 
 #[derive(Clone, PartialEq)]
+/// # Resource record
+///
+/// ## Structure
+/// NAME: Owner name
+///
+/// TYPE (2 bytes), CLASS (2 bytes), TTL (4 bytes)
+///
+/// RDLENGTH (2 bytes)
+///
+/// RDATA: Content depending on the type
 pub struct ResourceRecord {
     pub name: Name,
-    content: Vec<u8>,
+    content: [u8; 8],
+    pub rdata: RData,
 }
 
 impl ResourceRecord {
-    pub fn unserialize(stream: &[u8], offset: u16) -> (ResourceRecord, u16) {
-        let (name, content_begin) = Name::unserialize(stream, offset as usize).unwrap();
-        let rdlength: usize = (stream[content_begin + 8] as usize) << 8 | stream[content_begin + 9] as usize;
-        let content_end = content_begin + 10 + rdlength;
-
-        let mut content: Vec<u8> = vec![0; 10 + rdlength];
-        content.copy_from_slice(&stream[content_begin..content_end]);
-        return (
+    pub fn new(name: Name, rtype: TYPE, class: CLASS, ttl: u32, rdata: RData) -> Self {
+        let mut content: [u8; 8] = [0; 8];
+        content[0..2].copy_from_slice(&rtype.to_u16().to_be_bytes());
+        content[2..4].copy_from_slice(&class.to_u16().to_be_bytes());
+        content[4..8].copy_from_slice(&ttl.to_be_bytes());
+        Self {
+            name,
+            content,
+            rdata,
+        }
+    }
+
+    pub fn unserialize(stream: &[u8], offset: u16) -> Result<(ResourceRecord, u16), ParseError> {
+        let (name, content_begin) = Name::unserialize(stream, offset as usize)?;
+        let fields = stream.get(content_begin..content_begin + 10).ok_or(ParseError::Truncated)?;
+        let mut content: [u8; 8] = [0; 8];
+        content.copy_from_slice(&fields[0..8]);
+        let rdlength: usize = (fields[8] as usize) << 8 | fields[9] as usize;
+        let rdata_begin = content_begin + 10;
+
+        let rtype = TYPE::from_u16((content[0] as u16) << 8 | content[1] as u16);
+        let rdata = RData::unserialize(&rtype, stream, rdata_begin, rdlength)?;
+        Ok((
             ResourceRecord {
                 name,
                 content,
+                rdata,
             },
-            content_end as u16,
-        );
+            (rdata_begin + rdlength) as u16,
+        ))
     }
 
     pub fn get_type(&self) -> TYPE {
@@ -48,27 +76,26 @@ impl ResourceRecord {
         self.content[4..8].copy_from_slice(&ttl.to_be_bytes());
     }
 
+    /// Length of the RDATA once written without compression.
     pub fn get_rdata_length(&self) -> usize {
-        (self.content[8] as usize) << 8 | self.content[9] as usize
+        self.get_rdata().len()
     }
 
-    pub fn get_rdata(&self) -> &[u8] {
-        &self.content[10..10 + self.get_rdata_length()]
+    /// RDATA written without compression.
+    pub fn get_rdata(&self) -> Vec<u8> {
+        self.rdata.to_bytes()
     }
 
-    pub fn serialize(self, bytes: &mut Vec<u8>, lt: &mut LabelTree) {
+    pub fn serialize(&self, bytes: &mut Vec<u8>, lt: &mut LabelTree) {
         self.name.serialize(bytes, lt);
-        bytes.extend_from_slice(self.content.as_slice());
-    }
-
-    pub fn get_question(&self) -> Question {
-        let mut content: [u8; 4] = [0; 4];
-        content.copy_from_slice(&self.content[0..4]);
-
-        return Question {
-            qname: self.name.clone(),
-            content
-        };
+        bytes.extend_from_slice(&self.content);
+
+        // The length is only known once names in the RDATA are compressed.
+        let length_position = bytes.len();
+        bytes.extend_from_slice(&[0, 0]);
+        self.rdata.serialize(bytes, lt);
+        let rdlength = (bytes.len() - length_position - 2) as u16;
+        bytes[length_position..length_position + 2].copy_from_slice(&rdlength.to_be_bytes());
     }
 }
 
@@ -76,8 +103,8 @@ impl fmt::Display for ResourceRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "NAME: {}\nTYPE: {}\nCLASS: {}\nTTL: {}\nRDLENGTH: {}\nRDATA: {:?}\n",
-            self.name, self.get_type(), self.get_class(), self.get_ttl(), self.get_rdata_length(), self.get_rdata()
+            "NAME: {}\nTYPE: {}\nCLASS: {}\nTTL: {}\nRDLENGTH: {}\nRDATA: {}\n",
+            self.name, self.get_type(), self.get_class(), self.get_ttl(), self.get_rdata_length(), self.rdata
         )
     }
 }
@@ -91,6 +118,7 @@ impl fmt::Debug for ResourceRecord {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
 
     #[test]
     fn basic_unserialize_test() {
@@ -99,7 +127,7 @@ mod tests {
             0x00, 0x01, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x04, 172, 217, 13, 132,
         ];
         let expected_offset = rr_bytes.len();
-        let (rr, offset) = ResourceRecord::unserialize(&rr_bytes, 0);
+        let (rr, offset) = ResourceRecord::unserialize(&rr_bytes, 0).unwrap();
         assert_eq!(offset as usize, expected_offset);
         assert_eq!(rr.name.to_string(), "www.google.com");
         assert_eq!(rr.get_type().to_u16(), 1);
@@ -113,7 +141,7 @@ mod tests {
     fn weird_unserialize_test() {
         let rr_bytes = [0, 0, 0, 0, 0, 0, 0, 0, 0, 15, 0, 4, 127, 0, 0, 1];
         let expected_offset = rr_bytes.len();
-        let (rr, offset) = ResourceRecord::unserialize(&rr_bytes, 1);
+        let (rr, offset) = ResourceRecord::unserialize(&rr_bytes, 1).unwrap();
         assert_eq!(offset as usize, expected_offset);
         assert_eq!(rr.name.to_string(), "");
         assert_eq!(rr.get_type().to_u16(), 0);
@@ -123,16 +151,58 @@ mod tests {
         assert_eq!(rr.get_rdata(), [127, 0, 0, 1]);
     }
 
+    #[test]
+    fn truncated_unserialize_test() {
+        let rr_bytes = [0, 0x00, 0x01, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x04, 127, 0];
+        let result = ResourceRecord::unserialize(&rr_bytes, 0);
+        assert_eq!(result.err(), Some(ParseError::Truncated));
+
+        let rr_bytes = [0, 0x00, 0x01, 0x00, 0x01, 0, 0];
+        let result = ResourceRecord::unserialize(&rr_bytes, 0);
+        assert_eq!(result.err(), Some(ParseError::Truncated));
+    }
+
+    #[test]
+    fn bad_rdata_unserialize_test() {
+        // CNAME whose name goes past the RDATA
+        let rr_bytes = [0, 0x00, 0x05, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x03, 3, b'w', b'w', b'w', 0];
+        let result = ResourceRecord::unserialize(&rr_bytes, 0);
+        assert_eq!(result.err(), Some(ParseError::BadRData));
+
+        // Malformed content without names is kept as is
+        let rr_bytes = [0, 0x00, 0x01, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x03, 127, 0, 1];
+        let (rr, _) = ResourceRecord::unserialize(&rr_bytes, 0).unwrap();
+        assert_eq!(rr.get_rdata(), [127, 0, 1]);
+    }
+
+    #[test]
+    fn compressed_rdata_serialize_test() {
+        let rr = ResourceRecord::new(
+            Name::from("www.google.com"),
+            TYPE::CNAME,
+            CLASS::IN,
+            3600,
+            RData::CNAME(Name::from("google.com")),
+        );
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut lt = LabelTree::default();
+        rr.serialize(&mut bytes, &mut lt);
+        assert_eq!(bytes[16..], [0x00, 0x05, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x02, 0xC0, 0x04]);
+    }
+
     #[test]
     fn basic_serialize_test() {
         let rr_bytes = [
             3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0,
             0x00, 0x01, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x04, 172, 217, 13, 132,
         ];
-        let rr = ResourceRecord {
-            name: Name::from("www.google.com"),
-            content: vec![0x00, 0x01, 0x00, 0x01, 0, 0, 0x0E, 0x10, 0x00, 0x04, 172, 217, 13, 132]
-        };
+        let rr = ResourceRecord::new(
+            Name::from("www.google.com"),
+            TYPE::A,
+            CLASS::IN,
+            3600,
+            RData::A(Ipv4Addr::new(172, 217, 13, 132)),
+        );
         let mut bytes: Vec<u8> = Vec::with_capacity(rr_bytes.len());
         let mut lt = LabelTree::default();
         rr.serialize(&mut bytes, &mut lt);
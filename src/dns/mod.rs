@@ -2,3 +2,5 @@ pub mod cache;
 pub mod compression;
 pub mod dto;
 pub mod server;
+pub mod snapshot;
+pub mod tcp;
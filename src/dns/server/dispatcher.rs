@@ -1,27 +1,44 @@
 use crate::cli;
-use tokio::net::UdpSocket;
+use crate::dns::cache as Cache;
+use crate::dns::tcp;
+use super::upstream;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use super::responder;
 
+/// Time after which an idle TCP connection is closed by the server.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub async fn start() {
-    // TODO: Add TCP implementation.
-    let addr = cli::Args::get_params().get_bind();
+    let args = cli::Args::get_params();
+    let addr = args.get_bind();
     let socket: UdpSocket = UdpSocket::bind(addr.to_string()).await
         .expect(&format!("couldn't bind to address: {}", addr.to_string()));
+    let listener: TcpListener = TcpListener::bind(addr).await
+        .unwrap_or_else(|_| panic!("couldn't bind TCP listener to address: {}", addr));
     let arc_socket = Arc::new(socket);
 
-    // Start on a thread to add tcp implementation?
+    tokio::spawn(Cache::sweep(args.get_cache_sweep_interval()));
+    tokio::spawn(upstream::check_health());
+    tokio::spawn(dispatch_tcp_connections(listener));
     dispatch_udp_requests(arc_socket).await;
 }
 
 async fn dispatch_udp_requests(arc_socket: Arc<UdpSocket>) {
+    // Clients may not send more than the payload size advertised by the server.
+    let payload_size = cli::Args::get_params().get_edns_payload_size() as usize;
     loop {
-        let mut buf: [u8; 512] = [0; 512];
+        let mut buf: Vec<u8> = vec![0; payload_size];
         let arc_socket_clone = Arc::clone(&arc_socket);
 
         match (*arc_socket_clone).recv_from(&mut buf).await {
             Ok((bytes, client_address)) => {
+                buf.truncate(bytes);
                 log::info!(
                     "Received connection from {} of length {}",
                     client_address,
@@ -45,3 +62,50 @@ async fn dispatch_udp_requests(arc_socket: Arc<UdpSocket>) {
         }
     }
 }
+
+async fn dispatch_tcp_connections(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, client_address)) => {
+                log::info!("Accepted TCP connection from {}", client_address);
+                tokio::spawn(handle_tcp_connection(stream, client_address));
+            }
+            Err(e) => log::error!("{}", e),
+        }
+    }
+}
+
+/// Reads every query sent on a TCP connection.
+///
+/// Clients may pipeline several queries on the same connection (RFC 7766),
+/// so each one is handled on its own task and replies are written back
+/// as soon as they are ready, in any order.
+async fn handle_tcp_connection(stream: TcpStream, client_address: SocketAddr) {
+    let (mut reader, writer) = stream.into_split();
+    let arc_writer = Arc::new(Mutex::new(writer));
+
+    loop {
+        let message = match timeout(TCP_IDLE_TIMEOUT, tcp::read_message(&mut reader)).await {
+            Ok(Ok(Some(message))) => message,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                log::warn!("Couldn't read from {}: {}", client_address, e);
+                break;
+            }
+            Err(_) => {
+                log::debug!("Closing idle TCP connection from {}", client_address);
+                break;
+            }
+        };
+
+        log::info!(
+            "Received TCP query from {} of length {}",
+            client_address,
+            message.len()
+        );
+        let arc_writer_clone = Arc::clone(&arc_writer);
+        tokio::spawn(async move {
+            responder::handle_tcp(&message, client_address, arc_writer_clone).await;
+        });
+    }
+}
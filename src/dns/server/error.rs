@@ -0,0 +1,87 @@
+use core::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::dns::dto::error::ParseError;
+
+#[derive(Debug)]
+/// # Upstream error
+///
+/// Reason why a query could not be answered by an upstream server.
+///
+/// ## Behavior
+///
+/// The query is sent again, to the next server or after a backoff, and
+/// answered with a server failure (SERVFAIL) once every attempt failed.
+pub enum UpstreamError {
+    /// No socket could be bound to send the query from.
+    Bind(io::Error),
+
+    /// The server could not be connected to.
+    Connect(SocketAddr, io::Error),
+
+    /// The TLS handshake failed, for instance on an invalid certificate.
+    Tls(SocketAddr, io::Error),
+
+    /// The HTTP request failed.
+    Http(SocketAddr, hyper::Error),
+
+    /// The HTTP response has another status than 200 OK.
+    HttpStatus(SocketAddr, u16),
+
+    /// The query could not be sent.
+    Send(SocketAddr, io::Error),
+
+    /// The reply could not be received.
+    Receive(SocketAddr, io::Error),
+
+    /// The server did not reply in time.
+    Timeout(SocketAddr),
+
+    /// The server closed the TCP connection without replying.
+    Closed(SocketAddr),
+
+    /// The reply could not be read.
+    Malformed(SocketAddr, ParseError),
+
+    /// The reply does not answer the query, for the given reason.
+    Mismatched(SocketAddr, &'static str),
+
+    /// No server replied before the client timeout. The query goes on in
+    /// the background.
+    Deadline,
+
+    /// The query was cancelled before it was answered, for instance when
+    /// the server shuts down.
+    Cancelled,
+
+    /// The query was coalesced with an identical one, which failed.
+    Coalesced,
+
+    /// No upstream server is configured.
+    NoUpstream,
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Bind(e) => write!(f, "Couldn't create a receiving socket: {e}"),
+            UpstreamError::Connect(address, e) => write!(f, "Couldn't connect to {address}: {e}"),
+            UpstreamError::Tls(address, e) => write!(f, "TLS handshake with {address} failed: {e}"),
+            UpstreamError::Http(address, e) => write!(f, "HTTP request to {address} failed: {e}"),
+            UpstreamError::HttpStatus(address, status) => write!(f, "{address} replied with HTTP status {status}"),
+            UpstreamError::Send(address, e) => write!(f, "Couldn't send message to {address}: {e}"),
+            UpstreamError::Receive(address, e) => write!(f, "Couldn't receive message from {address}: {e}"),
+            UpstreamError::Timeout(address) => write!(f, "{address} did not reply in time"),
+            UpstreamError::Closed(address) => write!(f, "{address} closed the connection without replying"),
+            UpstreamError::Malformed(address, e) => write!(f, "Malformed reply from {address}: {e}"),
+            UpstreamError::Mismatched(address, reason) => write!(f, "Reply from {address} does not answer the query: {reason}"),
+            UpstreamError::Deadline => write!(f, "No upstream server replied before the client timeout"),
+            UpstreamError::Cancelled => write!(f, "The query was cancelled"),
+            UpstreamError::Coalesced => write!(f, "The identical query it waited for failed"),
+            UpstreamError::NoUpstream => write!(f, "No upstream server"),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
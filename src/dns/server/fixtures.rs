@@ -0,0 +1,18 @@
+//! Queries shared by the tests of the server.
+
+use crate::dns::dto::datagram::Datagram;
+
+/// Query for the A records of example.com, with the ID 0x1234.
+pub const QUERY: [u8; 29] = [
+    // header
+    0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // question
+    7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01,
+];
+
+/// `QUERY` with the ID `id`.
+pub fn query(id: u16) -> Datagram {
+    let mut query = Datagram::unserialize(&QUERY).unwrap();
+    query.header.set_id(id);
+    query
+}
@@ -0,0 +1,212 @@
+use crate::dns::dto::datagram::Datagram;
+use super::error::UpstreamError;
+use super::pool::validate_reply;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+
+/// Port of DNS-over-HTTPS servers.
+pub const DOH_PORT: u16 = 443;
+
+/// Media type of DNS messages in HTTP requests and responses (RFC 8484).
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Largest DNS message accepted in a response.
+const MAX_MESSAGE_SIZE: usize = 65_535;
+
+/// # DNS-over-HTTPS upstream (RFC 8484)
+///
+/// Queries are sent with POST requests on a single HTTP/2 connection,
+/// opened on first use and shared by every query as separate streams.
+/// The next query opens a new connection once the server closed it.
+pub struct HttpsUpstream {
+    address: SocketAddr,
+    url: Uri,
+
+    /// Host of the URL, sent with SNI, which the certificate of the server
+    /// must be valid for whatever address it is reached at.
+    name: ServerName<'static>,
+    connector: TlsConnector,
+    sender: Mutex<Option<SendRequest<Full<Bytes>>>>,
+}
+
+impl HttpsUpstream {
+    /// The configuration must offer HTTP/2 with ALPN.
+    pub fn new(address: SocketAddr, url: Uri, name: ServerName<'static>, config: Arc<ClientConfig>) -> Self {
+        Self {
+            address,
+            url,
+            name,
+            connector: TlsConnector::from(config),
+            sender: Mutex::new(None),
+        }
+    }
+
+    pub async fn query(&self, datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+        let address = self.address;
+        let mut query = datagram.clone();
+        // Identical queries have identical requests, which HTTP caches can share.
+        query.header.set_id(0);
+        let request = Request::post(self.url.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Full::new(Bytes::from(query.serialize())))
+            .expect("Unable to build the DNS-over-HTTPS request");
+
+        let mut sender = self.connect().await?;
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| UpstreamError::Http(address, e))?;
+        if response.status() != StatusCode::OK {
+            return Err(UpstreamError::HttpStatus(address, response.status().as_u16()));
+        }
+        if response.headers().get(CONTENT_TYPE).is_none_or(|content_type| content_type != DNS_MESSAGE) {
+            return Err(UpstreamError::Mismatched(address, "not a DNS message"));
+        }
+        let body = Limited::new(response.into_body(), MAX_MESSAGE_SIZE)
+            .collect()
+            .await
+            .map_err(|e| UpstreamError::Receive(address, io::Error::other(e)))?
+            .to_bytes();
+
+        let mut reply = Datagram::unserialize(&body).map_err(|e| UpstreamError::Malformed(address, e))?;
+        validate_reply(&reply.header, reply.questions.first(), 0, &query.questions[0])
+            .map_err(|reason| UpstreamError::Mismatched(address, reason))?;
+        reply.header.set_id(datagram.header.get_id());
+        Ok(reply)
+    }
+
+    /// Returns a handle to send requests on the HTTP/2 connection, opening
+    /// a new connection once the server closed the previous one.
+    async fn connect(&self) -> Result<SendRequest<Full<Bytes>>, UpstreamError> {
+        let mut sender = self.sender.lock().await;
+        if let Some(open) = sender.as_ref().filter(|open| !open.is_closed()) {
+            return Ok(open.clone());
+        }
+
+        let stream = TcpStream::connect(self.address)
+            .await
+            .map_err(|e| UpstreamError::Connect(self.address, e))?;
+        let stream = self
+            .connector
+            .connect(self.name.clone(), stream)
+            .await
+            .map_err(|e| UpstreamError::Tls(self.address, e))?;
+        let (open, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(|e| UpstreamError::Http(self.address, e))?;
+        log::debug!("Opened an HTTP/2 connection to {}", self.address);
+
+        let address = self.address;
+        tokio::spawn(async move {
+            match connection.await {
+                Ok(()) => log::debug!("{} closed the HTTP/2 connection", address),
+                Err(e) => log::warn!("HTTP/2 connection to {} failed: {}", address, e),
+            }
+        });
+        *sender = Some(open.clone());
+        Ok(open)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::server::fixtures::query;
+    use crate::dns::server::tls::tests::{certificate, TestCertificate};
+    use hyper::body::Incoming;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use rustls::ServerConfig;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Echoes DNS queries posted with the QR bit set, as `content_type`.
+    async fn answer(request: Request<Incoming>, content_type: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.uri().path(), "/dns-query");
+        assert_eq!(request.headers()[CONTENT_TYPE], DNS_MESSAGE);
+        let mut reply = request.into_body().collect().await.unwrap().to_bytes().to_vec();
+        assert_eq!(reply[..2], [0, 0]);
+        reply[2] |= 0x80;
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Full::new(Bytes::from(reply)))
+            .unwrap())
+    }
+
+    /// DNS-over-HTTPS server, returning its address and the number of
+    /// connections accepted.
+    async fn https_server(certificate: &TestCertificate, content_type: &'static str) -> (SocketAddr, Arc<AtomicUsize>) {
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificate.chain.clone(), certificate.key.clone_key())
+            .unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&connections);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::Relaxed);
+                let stream = acceptor.accept(stream).await.unwrap();
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service_fn(move |request| answer(request, content_type))),
+                );
+            }
+        });
+        (address, connections)
+    }
+
+    fn upstream(address: SocketAddr, certificate: &TestCertificate) -> HttpsUpstream {
+        let mut config = crate::dns::server::tls::client_config_with_roots(certificate.roots.clone(), vec![]);
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let url: Uri = format!("https://dns.test:{}/dns-query", address.port()).parse().unwrap();
+        HttpsUpstream::new(address, url, ServerName::try_from("dns.test").unwrap(), Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let certificate = certificate("dns.test");
+        let (address, connections) = https_server(&certificate, DNS_MESSAGE).await;
+        let upstream = upstream(address, &certificate);
+
+        let reply = upstream.query(&query(1)).await.unwrap();
+        assert_eq!(reply.header.get_id(), 1);
+        assert!(reply.questions[0] == query(1).questions[0]);
+
+        // Queries share the connection
+        let (first, second) = (query(2), query(3));
+        let (first_reply, second_reply) = tokio::join!(upstream.query(&first), upstream.query(&second));
+        assert_eq!(first_reply.unwrap().header.get_id(), 2);
+        assert_eq!(second_reply.unwrap().header.get_id(), 3);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_content_type() {
+        let certificate = certificate("dns.test");
+        let (address, _) = https_server(&certificate, "text/html").await;
+        let upstream = upstream(address, &certificate);
+
+        assert!(matches!(upstream.query(&query(1)).await, Err(UpstreamError::Mismatched(..))));
+    }
+}
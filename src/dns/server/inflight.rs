@@ -0,0 +1,184 @@
+use crate::dns::cache::Key;
+use crate::dns::dto::datagram::Datagram;
+use super::error::UpstreamError;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+lazy_static! {
+    static ref IN_FLIGHT: InFlight = InFlight::default();
+}
+
+/// Resolves `datagram` with `query`, unless the same question is already
+/// being resolved, in which case its reply is shared.
+pub async fn coalesce<F, Fut>(datagram: &Datagram, query: F) -> Result<Datagram, UpstreamError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Datagram, UpstreamError>>,
+{
+    IN_FLIGHT.resolve(datagram, query).await
+}
+
+/// # In-flight queries
+///
+/// Queries sent upstream and not answered yet, keyed like the cache, so
+/// that a question asked by many clients at once is only sent upstream once.
+///
+/// ## Behavior
+///
+/// The first query of a question is sent upstream. The next ones wait for
+/// its reply, which they get with their own ID and question, or fail with it.
+#[derive(Default)]
+pub struct InFlight {
+    queries: Mutex<HashMap<Key, broadcast::Sender<Option<Datagram>>>>,
+}
+
+impl InFlight {
+    pub async fn resolve<F, Fut>(&self, datagram: &Datagram, query: F) -> Result<Datagram, UpstreamError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Datagram, UpstreamError>>,
+    {
+        let key = Key::from_query(datagram);
+        let receiver = {
+            let mut queries = self.queries.lock().unwrap();
+            match queries.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    queries.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+
+        let reply = match receiver {
+            Some(mut receiver) => {
+                log::debug!("Waiting for the pending query of {}", datagram.questions[0].qname);
+                // The channel is closed without a reply if the first query is cancelled.
+                receiver.recv().await.ok().flatten().ok_or(UpstreamError::Coalesced)?
+            }
+            None => self.lead(key, query).await?,
+        };
+        Ok(with_query_id(datagram, reply))
+    }
+
+    /// Sends the query upstream, then hands the reply to the queries that
+    /// waited for it.
+    async fn lead<F, Fut>(&self, key: Key, query: F) -> Result<Datagram, UpstreamError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Datagram, UpstreamError>>,
+    {
+        let mut guard = LeaderGuard {
+            in_flight: self,
+            key: Some(key),
+        };
+        let result = query().await;
+        if let Some(sender) = guard.finish() {
+            // Nobody may be waiting.
+            let _ = sender.send(result.as_ref().ok().cloned());
+        }
+        result
+    }
+}
+
+/// Copies the ID and question of the query into a reply shared with it.
+fn with_query_id(datagram: &Datagram, mut reply: Datagram) -> Datagram {
+    reply.header.set_id(datagram.header.get_id());
+    if let Some(question) = reply.questions.first_mut() {
+        *question = datagram.questions[0].clone();
+    }
+    reply
+}
+
+/// Removes the entry of a query when it is done or cancelled, so that the
+/// next query of its question is sent upstream.
+struct LeaderGuard<'a> {
+    in_flight: &'a InFlight,
+    key: Option<Key>,
+}
+
+impl LeaderGuard<'_> {
+    fn finish(&mut self) -> Option<broadcast::Sender<Option<Datagram>>> {
+        let key = self.key.take()?;
+        self.in_flight.queries.lock().unwrap().remove(&key)
+    }
+}
+
+impl Drop for LeaderGuard<'_> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dto::name::Name;
+    use crate::dns::server::fixtures;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::task::JoinSet;
+
+    fn query(id: u16, name: &str) -> Datagram {
+        let mut query = fixtures::query(id);
+        query.questions[0].qname = Name::from(name);
+        query
+    }
+
+    async fn upstream(datagram: &Datagram, count: &AtomicUsize) -> Result<Datagram, UpstreamError> {
+        count.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut reply = datagram.clone();
+        reply.header.set_question(false);
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let in_flight = Arc::new(InFlight::default());
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut replies = JoinSet::new();
+        for id in 0..50 {
+            let query = query(id, if id % 2 == 0 { "example.com" } else { "EXAMPLE.com" });
+            let in_flight = Arc::clone(&in_flight);
+            let count = Arc::clone(&count);
+            replies.spawn(async move {
+                let reply = in_flight.resolve(&query, || upstream(&query, &count)).await;
+                (query, reply)
+            });
+        }
+        while let Some(result) = replies.join_next().await {
+            let (query, reply) = result.unwrap();
+            let reply = reply.unwrap();
+            assert_eq!(reply.header.get_id(), query.header.get_id());
+            assert_eq!(reply.questions[0].qname.to_string(), query.questions[0].qname.to_string());
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(in_flight.queries.lock().unwrap().is_empty());
+
+        // Done queries are sent upstream again
+        let query = query(50, "example.com");
+        in_flight.resolve(&query, || upstream(&query, &count)).await.unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_failure() {
+        let in_flight = InFlight::default();
+        let first = query(1, "example.com");
+        let second = query(2, "example.com");
+        let (first_reply, second_reply) = tokio::join!(
+            in_flight.resolve(&first, || async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Err(UpstreamError::NoUpstream)
+            }),
+            in_flight.resolve(&second, || async { unreachable!() }),
+        );
+        assert!(matches!(first_reply, Err(UpstreamError::NoUpstream)));
+        assert!(matches!(second_reply, Err(UpstreamError::Coalesced)));
+    }
+}
@@ -1,2 +1,11 @@
 pub mod dispatcher;
+pub mod error;
+pub mod https;
+pub mod inflight;
+pub mod pool;
 pub mod responder;
+pub mod tls;
+pub mod upstream;
+
+#[cfg(test)]
+mod fixtures;
@@ -0,0 +1,482 @@
+use crate::dns::dto::{header::Header, question::Question};
+use super::error::UpstreamError;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, OnceCell};
+use tokio::task::JoinHandle;
+
+/// Sockets opened for each address family.
+const SOCKET_COUNT: usize = 8;
+
+/// Queries after which a socket is replaced by one on another port.
+const MAX_SOCKET_QUERIES: usize = 8;
+
+/// Time after which a socket is replaced by one on another port.
+const MAX_SOCKET_AGE: Duration = Duration::from_secs(10);
+
+/// Largest datagram that can be received over UDP.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+static DEFAULT_SOCKET: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+static DEFAULT_SOCKET_V6: SocketAddr = SocketAddr::new(std::net::IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+
+/// # Socket pool
+///
+/// Sockets shared by every query sent to upstream servers over UDP, opened
+/// on first use.
+///
+/// ## Source ports
+///
+/// Each query is sent from a socket picked at random. A socket is replaced
+/// by one bound to a new port chosen by the system after
+/// `MAX_SOCKET_QUERIES` queries or `MAX_SOCKET_AGE`, so that source ports
+/// keep changing and are as hard to guess as IDs. Replaced sockets are
+/// closed once their pending queries are answered.
+///
+/// ## Matching
+///
+/// Each query is sent with a random ID that is not used by another pending
+/// query of its socket. Replies are handed to the pending query with the
+/// same ID, and dropped and counted unless they come from the server the
+/// query was sent to, have the QR bit set and repeat the question of the
+/// query, so that spoofed replies do not end up in the cache.
+#[derive(Default)]
+pub struct SocketPool {
+    v4: OnceCell<Vec<Mutex<Arc<PooledSocket>>>>,
+    v6: OnceCell<Vec<Mutex<Arc<PooledSocket>>>>,
+
+    /// Replies dropped by every socket of the pool.
+    dropped: Arc<AtomicU64>,
+}
+
+impl SocketPool {
+    /// Sends `query` to `upstream_addr`, rewriting its ID, and waits for the
+    /// reply. The reply keeps the ID the query was sent with.
+    pub async fn query(&self, query: &[u8], question: &Question, upstream_addr: SocketAddr) -> Result<Vec<u8>, UpstreamError> {
+        let (slots, address) = if upstream_addr.is_ipv4() {
+            (self.v4.get_or_try_init(|| async { self.open(DEFAULT_SOCKET) }).await?, DEFAULT_SOCKET)
+        } else {
+            (self.v6.get_or_try_init(|| async { self.open(DEFAULT_SOCKET_V6) }).await?, DEFAULT_SOCKET_V6)
+        };
+        let slot = &slots[rand::thread_rng().gen_range(0..slots.len())];
+        let socket = {
+            let mut socket = slot.lock().unwrap();
+            if socket.is_worn() {
+                log::debug!("Replacing the upstream socket on port {}", socket.port);
+                *socket = PooledSocket::bind(address, &self.dropped)?;
+            }
+            socket.queries.fetch_add(1, Ordering::Relaxed);
+            Arc::clone(&socket)
+        };
+        socket.query(query, question, upstream_addr).await
+    }
+
+    fn open(&self, address: SocketAddr) -> Result<Vec<Mutex<Arc<PooledSocket>>>, UpstreamError> {
+        (0..SOCKET_COUNT)
+            .map(|_| PooledSocket::bind(address, &self.dropped).map(Mutex::new))
+            .collect()
+    }
+}
+
+struct PooledSocket {
+    socket: Arc<UdpSocket>,
+    pending: Arc<PendingQueries>,
+    port: u16,
+    opened: Instant,
+
+    /// Queries sent from the socket.
+    queries: AtomicUsize,
+    receiver: JoinHandle<()>,
+}
+
+impl PooledSocket {
+    /// Binds a socket to a port chosen by the system.
+    fn bind(address: SocketAddr, dropped: &Arc<AtomicU64>) -> Result<Arc<Self>, UpstreamError> {
+        let socket = std::net::UdpSocket::bind(address).map_err(UpstreamError::Bind)?;
+        socket.set_nonblocking(true).map_err(UpstreamError::Bind)?;
+        let socket = Arc::new(UdpSocket::from_std(socket).map_err(UpstreamError::Bind)?);
+        let port = socket.local_addr().map_err(UpstreamError::Bind)?.port();
+        let pending = Arc::new(PendingQueries::new(Arc::clone(dropped)));
+        let receiver = tokio::spawn(receive(Arc::clone(&socket), Arc::clone(&pending)));
+        Ok(Arc::new(Self {
+            socket,
+            pending,
+            port,
+            opened: Instant::now(),
+            queries: AtomicUsize::new(0),
+            receiver,
+        }))
+    }
+
+    /// Whether the socket should be replaced before its next query.
+    fn is_worn(&self) -> bool {
+        self.queries.load(Ordering::Relaxed) >= MAX_SOCKET_QUERIES || self.opened.elapsed() >= MAX_SOCKET_AGE
+    }
+
+    async fn query(&self, query: &[u8], question: &Question, upstream_addr: SocketAddr) -> Result<Vec<u8>, UpstreamError> {
+        let socket = &self.socket;
+        self.pending
+            .query(query, question, upstream_addr, |query| async move {
+                socket
+                    .send_to(&query, upstream_addr)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| UpstreamError::Send(upstream_addr, e))
+            })
+            .await
+    }
+}
+
+impl Drop for PooledSocket {
+    /// Closes the socket, once it was replaced and its last query is done.
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, pending: Arc<PendingQueries>) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (length, source) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::error!("Couldn't receive message from upstream: {}", e);
+                continue;
+            }
+        };
+        pending.dispatch(&buf[..length], source);
+    }
+}
+
+/// Query waiting for its reply.
+struct Pending {
+    upstream_addr: SocketAddr,
+    question: Question,
+    sender: oneshot::Sender<Vec<u8>>,
+
+    /// Registration of the query, told apart from a later one with the same ID.
+    registration: u64,
+}
+
+/// # Pending queries
+///
+/// Queries sent on a socket or a connection and waiting for their reply,
+/// by ID.
+pub struct PendingQueries {
+    queries: Mutex<Queries>,
+
+    /// Replies dropped, shared with other sockets or connections.
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Queries {
+    by_id: HashMap<u16, Pending>,
+
+    /// Set when the connection is closed, so that no query waits on it.
+    closed: bool,
+
+    /// Queries registered so far.
+    registrations: u64,
+}
+
+impl Queries {
+    /// Registers a query under `id`, returning its registration.
+    fn insert(&mut self, id: u16, upstream_addr: SocketAddr, question: &Question, sender: oneshot::Sender<Vec<u8>>) -> u64 {
+        self.registrations += 1;
+        let registration = self.registrations;
+        self.by_id.insert(id, Pending { upstream_addr, question: question.clone(), sender, registration });
+        registration
+    }
+}
+
+impl PendingQueries {
+    pub fn new(dropped: Arc<AtomicU64>) -> Self {
+        Self {
+            queries: Mutex::new(Queries::default()),
+            dropped,
+        }
+    }
+
+    /// Sends `query` with `send`, rewritten with a random ID that no other
+    /// pending query uses, and waits for its reply.
+    ///
+    /// Fails without sending the query if the connection is closed.
+    pub async fn query<F, Fut>(&self, query: &[u8], question: &Question, upstream_addr: SocketAddr, send: F) -> Result<Vec<u8>, UpstreamError>
+    where
+        F: FnOnce(Vec<u8>) -> Fut,
+        Fut: Future<Output = Result<(), UpstreamError>>,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let (id, registration) = self.register(upstream_addr, question, sender)?;
+        // Unregisters the query if it is cancelled, for instance on timeout.
+        let _guard = PendingGuard { pending: self, id, registration };
+
+        let mut query = query.to_vec();
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        send(query).await?;
+        // The sender is dropped without a reply when the connection is closed.
+        receiver.await.map_err(|_| UpstreamError::Closed(upstream_addr))
+    }
+
+    fn register(&self, upstream_addr: SocketAddr, question: &Question, sender: oneshot::Sender<Vec<u8>>) -> Result<(u16, u64), UpstreamError> {
+        let mut queries = self.queries.lock().unwrap();
+        if queries.closed {
+            return Err(UpstreamError::Closed(upstream_addr));
+        }
+        let mut rng = rand::thread_rng();
+        let mut id: u16 = rng.gen();
+        while queries.by_id.contains_key(&id) {
+            id = rng.gen();
+        }
+        let registration = queries.insert(id, upstream_addr, question, sender);
+        Ok((id, registration))
+    }
+
+    /// Hands a reply to the pending query it answers.
+    pub fn dispatch(&self, reply: &[u8], source: SocketAddr) {
+        let Ok(header) = Header::unserialize(reply) else {
+            return self.drop_reply(source, "no header");
+        };
+        let mut queries = self.queries.lock().unwrap();
+        let Some(query) = queries.by_id.get(&header.get_id()) else {
+            return self.drop_reply(source, "no pending query with its ID");
+        };
+        if source != query.upstream_addr {
+            return self.drop_reply(source, "sent by another server");
+        }
+        let question = Question::unserialize(reply, 12).ok().map(|(question, _)| question);
+        if let Err(reason) = validate_reply(&header, question.as_ref(), header.get_id(), &query.question) {
+            return self.drop_reply(source, reason);
+        }
+
+        let query = queries.by_id.remove(&header.get_id()).unwrap();
+        // The query may have been cancelled in the meantime.
+        let _ = query.sender.send(reply.to_vec());
+    }
+
+    /// Fails every pending query and the next ones, when their connection
+    /// is closed.
+    pub fn close(&self) {
+        let mut queries = self.queries.lock().unwrap();
+        queries.closed = true;
+        queries.by_id.clear();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queries.lock().unwrap().closed
+    }
+
+    fn drop_reply(&self, source: SocketAddr, reason: &str) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        log::debug!("Dropped a reply from {}: {} ({} dropped so far)", source, reason, dropped);
+    }
+}
+
+/// Checks that a reply answers the query with the ID `id` and the question
+/// `expected`, returning the reason why it does not otherwise.
+pub fn validate_reply(header: &Header, question: Option<&Question>, id: u16, expected: &Question) -> Result<(), &'static str> {
+    if header.get_id() != id {
+        return Err("wrong ID");
+    }
+    if header.is_question() {
+        return Err("QR bit not set");
+    }
+    if header.question_count() != 1 || question != Some(expected) {
+        return Err("wrong question");
+    }
+    Ok(())
+}
+
+struct PendingGuard<'a> {
+    pending: &'a PendingQueries,
+    id: u16,
+    registration: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        // Once answered, the ID may have been given to another query.
+        let mut queries = self.pending.queries.lock().unwrap();
+        if queries.by_id.get(&self.id).is_some_and(|query| query.registration == self.registration) {
+            queries.by_id.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dto::name::Name;
+
+    fn question(name: &str) -> Question {
+        Question {
+            qname: Name::from(name),
+            content: [0, 1, 0, 1],
+        }
+    }
+
+    fn query(question: &Question) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        question.serialize(&mut bytes, &mut Default::default());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries() {
+        // Replies to the queries in the reverse order
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let mut queries = Vec::new();
+            for _ in 0..2 {
+                let (length, client) = upstream.recv_from(&mut buf).await.unwrap();
+                queries.push((buf[..length].to_vec(), client));
+            }
+            for (mut reply, client) in queries.into_iter().rev() {
+                reply[2] |= 0x80;
+                upstream.send_to(&reply, client).await.unwrap();
+            }
+        });
+
+        let pool = SocketPool::default();
+        let first = question("first.example.com");
+        let second = question("second.example.com");
+        let (first_query, second_query) = (query(&first), query(&second));
+        let (first_reply, second_reply) = tokio::join!(
+            pool.query(&first_query, &first, upstream_addr),
+            pool.query(&second_query, &second, upstream_addr),
+        );
+        let first_reply = first_reply.unwrap();
+        let second_reply = second_reply.unwrap();
+        assert!(Question::unserialize(&first_reply, 12).unwrap().0 == first);
+        assert!(Question::unserialize(&second_reply, 12).unwrap().0 == second);
+        // Answered queries are unregistered
+        assert!(pool.v4.get().unwrap().iter().all(|slot| slot.lock().unwrap().pending.queries.lock().unwrap().by_id.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_source_ports() {
+        const QUERIES: usize = 100;
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let ports = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let mut ports = HashMap::new();
+            for _ in 0..QUERIES {
+                let (length, client) = upstream.recv_from(&mut buf).await.unwrap();
+                *ports.entry(client.port()).or_insert(0) += 1;
+                buf[2] |= 0x80;
+                upstream.send_to(&buf[..length], client).await.unwrap();
+            }
+            ports
+        });
+
+        let pool = SocketPool::default();
+        let question = question("example.com");
+        let query = query(&question);
+        for _ in 0..QUERIES {
+            pool.query(&query, &question, upstream_addr).await.unwrap();
+        }
+        // Each socket is replaced by one on another port after its last query
+        let ports = ports.await.unwrap();
+        assert!(ports.len() >= QUERIES.div_ceil(MAX_SOCKET_QUERIES), "{} ports", ports.len());
+        assert!(ports.values().all(|&count| count <= MAX_SOCKET_QUERIES));
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_replies() {
+        // Sends replies that do not answer the query, then the right one
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (length, client) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = buf[..length].to_vec();
+            let mut reply = query.clone();
+            reply[2] |= 0x80;
+
+            let mut wrong_question = reply[..12].to_vec();
+            question("spoofed.example.com").serialize(&mut wrong_question, &mut Default::default());
+            let mut wrong_id = reply.clone();
+            wrong_id[1] = wrong_id[1].wrapping_add(1);
+            upstream.send_to(&wrong_question, client).await.unwrap();
+            upstream.send_to(&query, client).await.unwrap();
+            upstream.send_to(&wrong_id, client).await.unwrap();
+            spoofer.send_to(&reply, client).await.unwrap();
+            upstream.send_to(&reply, client).await.unwrap();
+        });
+
+        let pool = SocketPool::default();
+        let question = question("example.com");
+        let reply = pool.query(&query(&question), &question, upstream_addr).await.unwrap();
+        assert!(Question::unserialize(&reply, 12).unwrap().0 == question);
+        assert_eq!(pool.dropped.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_query() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let pool = SocketPool::default();
+        let question = question("example.com");
+        let query = query(&question);
+        let result = tokio::time::timeout(
+            Duration::from_millis(50),
+            pool.query(&query, &question, upstream.local_addr().unwrap()),
+        );
+        assert!(result.await.is_err());
+        assert!(pool.v4.get().unwrap().iter().all(|slot| slot.lock().unwrap().pending.queries.lock().unwrap().by_id.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_reused_id() {
+        let pending = PendingQueries::new(Arc::default());
+        let upstream_addr: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let question = question("example.com");
+        let (sender, mut receiver) = oneshot::channel();
+        let reply = pending
+            .query(&query(&question), &question, upstream_addr, |mut query| {
+                // Answers the query, whose ID is then given to another one
+                // before the first one is unregistered
+                query[2] |= 0x80;
+                pending.dispatch(&query, upstream_addr);
+                let id = u16::from_be_bytes([query[0], query[1]]);
+                pending.queries.lock().unwrap().insert(id, upstream_addr, &question, sender);
+                async { Ok(()) }
+            })
+            .await;
+        assert!(reply.is_ok());
+
+        let mut queries = pending.queries.lock().unwrap();
+        assert_eq!(queries.by_id.len(), 1);
+        let (_, other) = queries.by_id.drain().next().unwrap();
+        other.sender.send(vec![]).unwrap();
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let pending = PendingQueries::new(Arc::default());
+        let upstream_addr: SocketAddr = "127.0.0.1:853".parse().unwrap();
+        let question = question("example.com");
+        let query = query(&question);
+        let (result, _) = tokio::join!(
+            pending.query(&query, &question, upstream_addr, |_| async { Ok(()) }),
+            async { pending.close() },
+        );
+        assert!(matches!(result, Err(UpstreamError::Closed(_))));
+
+        // Queries on a closed connection fail without being sent
+        let result = pending.query(&query, &question, upstream_addr, |_| async { unreachable!() }).await;
+        assert!(matches!(result, Err(UpstreamError::Closed(_))));
+    }
+}
@@ -1,39 +1,49 @@
-use crate::cli;
+use crate::cli::{self, BlockPolicy};
 use crate::dns::cache as Cache;
+use crate::dns::tcp;
+use super::error::UpstreamError;
+use super::inflight;
+use super::upstream;
 use crate::dns::dto::{
     datagram::Datagram,
     enums::TYPE,
-    header::RCODE,
+    error::ParseError,
+    header::{Header, RCODE},
+    opt::Opt,
 };
-use std::net::{SocketAddr, Ipv4Addr};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::net::UdpSocket;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::Mutex;
 
 pub async fn handle(buf: &[u8], address: SocketAddr, socket: Arc<UdpSocket>) {
     let recv_time = Instant::now();
-    let datagram = Datagram::unserialize(&buf);
-    log::debug!("Rcvd pkt from {}\n{}", address, datagram);
-
-    // TODO: Handle questions async?
-    // or maybe prepare all questions asynchronously, then
-    // merge them for a single request containing all requests.
-    // for i in 0..datagram.header.qdcount as usize {
-    let question = &datagram.questions[0];
-    let reply: Datagram;
+    let bytes = match parse_query(buf) {
+        Ok(datagram) => {
+            let reply = resolve(&datagram, &address).await;
 
-    // TODO: Should probably match opcode first.
-    match question.get_type() {
-        TYPE::A | TYPE::AAAA => {
-            reply = respond_question(&datagram, &address).await;
-        }
-        _ => {
-            // Forward request as normal if function type not supported
-            reply = get_forwarded_answer(&datagram).await.unwrap();
+            let max_size = get_udp_payload_size(&datagram);
+            let mut bytes = reply.serialize();
+            if bytes.len() > max_size {
+                log::debug!(
+                    "Reply to {} is {} bytes long, truncating to {} bytes",
+                    address,
+                    bytes.len(),
+                    max_size
+                );
+                bytes = truncate(&reply).serialize();
+            }
+            bytes
         }
-    }
+        Err(e) => match format_error_answer(buf, &e, &address) {
+            Some(reply) => reply.serialize(),
+            None => return,
+        },
+    };
 
-    match socket.send_to(&reply.serialize(), address).await {
+    match socket.send_to(&bytes, address).await {
         Ok(_) => {
             log::debug!(
                 "Sent reply to {} in {} ms",
@@ -50,34 +60,271 @@ pub async fn handle(buf: &[u8], address: SocketAddr, socket: Arc<UdpSocket>) {
     }
 }
 
-async fn respond_question(datagram: &Datagram, address: &SocketAddr) -> Datagram {
-    if let Some(blocked_answer) = get_blocked_answer(datagram) {
-        log::info!(
-            "Blocked {} for {}",
-            datagram.questions.get(0).unwrap().qname,
-            address
-        );
-        return blocked_answer;
+pub async fn handle_tcp(buf: &[u8], address: SocketAddr, stream: Arc<Mutex<OwnedWriteHalf>>) {
+    let recv_time = Instant::now();
+    let reply = match parse_query(buf) {
+        Ok(datagram) => resolve(&datagram, &address).await,
+        Err(e) => match format_error_answer(buf, &e, &address) {
+            Some(reply) => reply,
+            None => return,
+        },
+    };
+
+    let mut writer = stream.lock().await;
+    match tcp::write_message(&mut *writer, &reply.serialize()).await {
+        Ok(_) => {
+            log::debug!(
+                "Sent TCP reply to {} in {} ms",
+                address,
+                recv_time.elapsed().as_millis()
+            )
+        },
+        Err(_) => {
+            log::warn!(
+                "Couldn't reply to {} over TCP",
+                address
+            )
+        }
+    }
+}
+
+/// Reads a query, which must contain at least one question.
+fn parse_query(buf: &[u8]) -> Result<Datagram, ParseError> {
+    let datagram = Datagram::unserialize(buf)?;
+    if datagram.questions.is_empty() {
+        return Err(ParseError::BadCount);
+    }
+    Ok(datagram)
+}
+
+/// Answers a malformed query with FORMERR.
+///
+/// Returns `None` if not even the header could be read, as there is
+/// no ID to reply to.
+fn format_error_answer(buf: &[u8], error: &ParseError, address: &SocketAddr) -> Option<Datagram> {
+    log::warn!("Malformed query from {}: {}", address, error);
+    let mut header = Header::unserialize(buf).ok()?;
+    header.set_question(false);
+    header.set_authoritative_answer(false);
+    header.set_truncated(false);
+    header.set_recursion_available(true);
+    header.set_rcode(RCODE::FormError);
+    header.set_question_count(0);
+    header.set_answer_count(0);
+    header.set_authority_count(0);
+    header.set_additional_count(0);
+    Some(Datagram {
+        header,
+        questions: vec![],
+        answers: vec![],
+        authorities: vec![],
+        additionals: vec![],
+    })
+}
+
+async fn resolve(datagram: &Datagram, address: &SocketAddr) -> Datagram {
+    log::debug!("Rcvd pkt from {}\n{}", address, datagram);
+
+    if let Some(opt) = datagram.get_opt() {
+        if opt.version > 0 {
+            log::debug!("Unsupported EDNS version {} from {}", opt.version, address);
+            return bad_version_answer(datagram);
+        }
+    }
+
+    let mut reply = if datagram.questions.len() == 1 {
+        resolve_question(datagram.clone(), *address).await.datagram
+    } else {
+        // Each question is resolved on its own, as most upstream servers
+        // refuse queries with more than one question.
+        let queries = split_questions(datagram);
+        let handles = queries
+            .iter()
+            .map(|query| tokio::spawn(resolve_question(query.clone(), *address)))
+            .collect::<Vec<_>>();
+        let mut replies: Vec<QuestionReply> = Vec::with_capacity(queries.len());
+        for (handle, query) in handles.into_iter().zip(queries.iter()) {
+            match handle.await {
+                Ok(reply) => replies.push(reply),
+                Err(e) => {
+                    log::error!("Couldn't resolve {}: {}", query.questions[0].qname, e);
+                    replies.push(QuestionReply {
+                        datagram: server_failure_answer(query),
+                        blocked: false,
+                    });
+                }
+            }
+        }
+        merge_replies(datagram, replies, &cli::Args::get_params().block_policy)
+    };
+
+    set_reply_opt(datagram, &mut reply);
+    reply
+}
+
+/// Reply to a single question of a query.
+struct QuestionReply {
+    datagram: Datagram,
+    blocked: bool,
+}
+
+async fn resolve_question(datagram: Datagram, address: SocketAddr) -> QuestionReply {
+    let question = &datagram.questions[0];
+
+    // TODO: Should probably match opcode first.
+    if let TYPE::A | TYPE::AAAA = question.get_type() {
+        if let Some(blocked_answer) = get_blocked_answer(&datagram) {
+            log::info!("Blocked {} for {}", question.qname, address);
+            return QuestionReply {
+                datagram: blocked_answer,
+                blocked: true,
+            };
+        }
+    }
+    // Other types are never blocked, but answered from the cache as well.
+    QuestionReply {
+        datagram: respond_question(&datagram, &address).await,
+        blocked: false,
+    }
+}
+
+/// Builds one single-question query per question, keeping the OPT record.
+fn split_questions(datagram: &Datagram) -> Vec<Datagram> {
+    datagram
+        .questions
+        .iter()
+        .map(|question| {
+            let mut query = Datagram {
+                header: datagram.header.clone(),
+                questions: vec![question.clone()],
+                answers: vec![],
+                authorities: vec![],
+                additionals: vec![],
+            };
+            query.set_opt(datagram.get_opt());
+            query.update_counts();
+            query
+        })
+        .collect()
+}
+
+/// Merges the replies to every question of a query into a single reply.
+///
+/// The first error of a question that is not blocked becomes the RCODE
+/// of the reply. Blocked questions follow the block policy: either the whole
+/// message is non-existent, or the question is left unanswered, unless
+/// every question is blocked.
+fn merge_replies(query: &Datagram, replies: Vec<QuestionReply>, policy: &BlockPolicy) -> Datagram {
+    let mut merged = empty_answer(query);
+    merged.questions = query.questions.clone();
+
+    let blocked_count = replies.iter().filter(|reply| reply.blocked).count();
+    if blocked_count > 0 && (*policy == BlockPolicy::Message || blocked_count == replies.len()) {
+        merged.update_counts();
+        return merged;
+    }
+
+    merged.header.set_rcode(RCODE::NoError);
+    for reply in replies.into_iter().filter(|reply| !reply.blocked) {
+        let rcode = reply.datagram.header.get_rcode();
+        if merged.header.get_rcode() == RCODE::NoError && rcode != RCODE::NoError {
+            merged.header.set_rcode(rcode);
+        }
+        for answer in reply.datagram.answers {
+            if !merged.answers.contains(&answer) {
+                merged.answers.push(answer);
+            }
+        }
+        for authority in reply.datagram.authorities {
+            if !merged.authorities.contains(&authority) {
+                merged.authorities.push(authority);
+            }
+        }
+        for additional in reply.datagram.additionals {
+            if additional.get_type() != TYPE::OPT && !merged.additionals.contains(&additional) {
+                merged.additionals.push(additional);
+            }
+        }
+    }
+    merged.update_counts();
+    merged
+}
+
+/// Largest reply that can be sent to the client over UDP.
+fn get_udp_payload_size(datagram: &Datagram) -> usize {
+    let client_size = datagram
+        .get_opt()
+        .map_or(Opt::MIN_PAYLOAD_SIZE, |opt| opt.get_payload_size());
+    client_size.min(cli::Args::get_params().get_edns_payload_size()) as usize
+}
+
+/// OPT records are hop-by-hop: the client only gets one if it sent one,
+/// and it advertises what this server accepts rather than what upstream does.
+fn set_reply_opt(query: &Datagram, reply: &mut Datagram) {
+    match query.get_opt() {
+        Some(query_opt) => {
+            let mut opt = reply.get_opt().unwrap_or_else(|| {
+                let mut opt = Opt::new(0);
+                opt.set_dnssec_ok(query_opt.is_dnssec_ok());
+                opt
+            });
+            opt.udp_payload_size = cli::Args::get_params().get_edns_payload_size();
+            opt.version = 0;
+            opt.options.clear();
+            reply.set_opt(Some(opt));
+        }
+        None => reply.set_opt(None),
     }
-    if let Some(cached_answer) = get_cached_answer(datagram) {
+}
+
+/// Strips every record but the OPT record, so that the client retries over TCP.
+fn truncate(reply: &Datagram) -> Datagram {
+    let mut truncated = Datagram {
+        header: reply.header.clone(),
+        questions: reply.questions.clone(),
+        answers: vec![],
+        authorities: vec![],
+        additionals: vec![],
+    };
+    truncated.header.set_truncated(true);
+    truncated.header.set_answer_count(0);
+    truncated.header.set_authority_count(0);
+    truncated.set_opt(reply.get_opt());
+    truncated
+}
+
+async fn respond_question(datagram: &Datagram, address: &SocketAddr) -> Datagram {
+    if let Some(entry) = Cache::get(&Cache::Key::from_query(datagram)) {
         log::debug!(
             "Cache hit on {} for {}",
-            datagram.questions.get(0).unwrap().qname,
+            datagram.questions[0].qname,
             address
         );
-        return cached_answer;
+        if entry.needs_prefetch() {
+            prefetch(datagram);
+        }
+        return get_answer_from_entry(datagram, entry);
     }
-    if let Some(forwarded_answer) = get_forwarded_answer(datagram).await {
-        log::debug!(
-            "Forwarded {} request for {}",
-            datagram.questions.get(0).unwrap().qname,
+    match get_forwarded_answer(datagram).await {
+        Ok(forwarded_answer) => {
+            log::debug!(
+                "Forwarded {} request for {}",
+                datagram.questions.get(0).unwrap().qname,
+                address
+            );
+            return forwarded_answer;
+        }
+        Err(e) => log::error!("Couldn't forward {} for {}: {}", datagram.questions[0].qname, address, e),
+    }
+    if let Some(stale_answer) = get_stale_answer(datagram) {
+        log::info!(
+            "Serving stale {} for {}",
+            datagram.questions[0].qname,
             address
         );
-        return forwarded_answer;
-    } else {
-        log::error!("Couldn't connect to upstream.");
-        return empty_answer(datagram);
+        return stale_answer;
     }
+    server_failure_answer(datagram)
 }
 
 fn get_blocked_answer(datagram: &Datagram) -> Option<Datagram> {
@@ -90,68 +337,73 @@ fn get_blocked_answer(datagram: &Datagram) -> Option<Datagram> {
     }
 }
 
-fn get_cached_answer(datagram: &Datagram) -> Option<Datagram> {
-    let question = &datagram.questions[0];
-    let cache_result = Cache::get(question);
-    match cache_result {
-        Some(answer) => {
-            let mut header = datagram.header.clone();
-            header.set_question(false);
-            header.set_recursion_available(true);
-            header.set_authoritative_answer(false);
-            header.set_truncated(false);
-            header.set_answer_count(1);
-            header.set_authority_count(0);
-            header.set_additional_count(0);
-            return Some(Datagram {
-                header,
-                questions: vec![question.clone()],
-                answers: vec![answer],
-                authorities: vec![],
-                additionals: vec![],
-            })
+/// Refreshes the cache entry of the question in the background.
+fn prefetch(datagram: &Datagram) {
+    let datagram = datagram.clone();
+    tokio::spawn(async move {
+        log::debug!("Prefetching {}", datagram.questions[0].qname);
+        if let Err(e) = forward_and_cache(&datagram).await {
+            log::warn!("Couldn't prefetch {}: {}", datagram.questions[0].qname, e);
         }
-        None => return None,
-    }
+    });
 }
 
-static DEFAULT_SOCKET: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-
-async fn get_forwarded_answer(datagram: &Datagram) -> Option<Datagram> {
-    // TODO: Add TCP capabilities logic
-    let upstream_addr: SocketAddr = cli::Args::get_params().get_upstream();
-    let client_socket: UdpSocket =
-        UdpSocket::bind(DEFAULT_SOCKET).await.expect("Couldn't create a receiving socket");
-    client_socket
-        .connect(upstream_addr).await
-        .expect(&format!("Couldn't connect to {}", upstream_addr));
-    client_socket
-        .send(&datagram.clone().serialize()).await
-        .expect(&format!("Couldn't send message to {}", upstream_addr));
-    let send_time = Instant::now();
-    log::debug!("Forwarded request to {}", upstream_addr);
+/// Answers from an expired entry, when upstream cannot be reached (RFC 8767).
+fn get_stale_answer(datagram: &Datagram) -> Option<Datagram> {
+    let entry = Cache::get_stale(&Cache::Key::from_query(datagram))?;
+    Some(get_answer_from_entry(datagram, entry))
+}
 
-    let mut buf = [0; 1024];
-    client_socket
-        .recv(&mut buf).await
-        .expect(&format!("Couldn't receive message from {}", upstream_addr));
+fn get_answer_from_entry(datagram: &Datagram, entry: Cache::Entry) -> Datagram {
+    let question = &datagram.questions[0];
+    let mut header = datagram.header.clone();
+    header.set_question(false);
+    header.set_recursion_available(true);
+    header.set_authoritative_answer(false);
+    header.set_truncated(false);
+    header.set_rcode(entry.rcode);
+    let mut answer = Datagram {
+        header,
+        questions: vec![question.clone()],
+        answers: entry.answers,
+        authorities: entry.authorities,
+        additionals: entry.additionals,
+    };
+    answer.update_counts();
+    answer
+}
 
-    let receiving_delay = send_time.elapsed().as_millis();
-    let reply = Datagram::unserialize(&buf);
+/// Forwards the query, waiting at most for the client timeout. The reply
+/// still gets cached if it comes later.
+async fn get_forwarded_answer(datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+    let query = datagram.clone();
+    let forward = tokio::spawn(async move { forward_and_cache(&query).await });
+    upstream::wait_for(forward, cli::Args::get_params().get_client_timeout()).await
+}
 
-    for i in 0..reply.header.answer_count() as usize {
-        let answer = reply.answers.get(i)?;
+/// Identical questions asked at the same time are only forwarded once.
+async fn forward_and_cache(datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+    inflight::coalesce(datagram, || async {
+        let reply = upstream::forward(datagram).await?;
+        Cache::insert(&Cache::Key::from_query(datagram), &reply);
+        Ok(reply)
+    })
+    .await
+}
 
-        Cache::insert(&answer.get_question(), answer.clone());
-    }
+fn server_failure_answer(datagram: &Datagram) -> Datagram {
+    let mut reply = empty_answer(datagram);
+    reply.header.set_rcode(RCODE::ServFail);
+    reply
+}
 
-    log::debug!(
-        "Received reply from {} in {} ms\n{}",
-        upstream_addr,
-        receiving_delay,
-        reply
-    );
-    return Some(reply);
+fn bad_version_answer(datagram: &Datagram) -> Datagram {
+    let mut reply = empty_answer(datagram);
+    reply.header.set_rcode(RCODE::NoError);
+    let mut opt = Opt::new(cli::Args::get_params().get_edns_payload_size());
+    opt.extended_rcode = (Opt::BADVERS >> 4) as u8;
+    reply.set_opt(Some(opt));
+    reply
 }
 
 fn empty_answer(datagram: &Datagram) -> Datagram {
@@ -161,6 +413,7 @@ fn empty_answer(datagram: &Datagram) -> Datagram {
     header.set_truncated(false);
     header.set_recursion_available(true);
     header.set_rcode(RCODE::NXDomain);
+    header.set_question_count(1);
     header.set_answer_count(0);
     header.set_authority_count(0);
     header.set_additional_count(0);
@@ -172,3 +425,204 @@ fn empty_answer(datagram: &Datagram) -> Datagram {
         additionals: vec![],
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dto::enums::CLASS;
+    use crate::dns::dto::name::Name;
+    use crate::dns::dto::rdata::RData;
+    use crate::dns::dto::resource_record::ResourceRecord;
+    use crate::dns::server::fixtures::QUERY;
+
+    #[tokio::test]
+    async fn test_stale_answer() {
+        let mut query = Datagram::unserialize(&QUERY).unwrap();
+        query.questions[0].qname = Name::from("stale.example.com");
+        let mut answer = answer_record();
+        answer.set_ttl(1);
+        let reply = question_reply(&query, RCODE::NoError, vec![answer], false).datagram;
+        Cache::insert(&Cache::Key::from_query(&query), &reply);
+        assert!(Cache::get(&Cache::Key::from_query(&query)).is_some());
+
+        tokio::time::sleep(std::time::Duration::from_millis(1010)).await;
+        assert!(Cache::get(&Cache::Key::from_query(&query)).is_none());
+        let stale = get_stale_answer(&query).unwrap();
+        assert_eq!(stale.header.get_id(), 0x1234);
+        assert_eq!(stale.answers[0].get_ttl(), Cache::STALE_TTL);
+
+        // Served when forwarding fails, as no upstream server is set in tests
+        let address: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let reply = respond_question(&query, &address).await;
+        assert!(reply.header.get_rcode() == RCODE::NoError);
+        assert_eq!(reply.answers[0].get_ttl(), Cache::STALE_TTL);
+
+        // Nothing cached, upstream failures are not reported as non-existent domains
+        assert!(server_failure_answer(&query).header.get_rcode() == RCODE::ServFail);
+    }
+
+    #[tokio::test]
+    async fn test_cached_mx() {
+        let mut query = Datagram::unserialize(&QUERY).unwrap();
+        query.questions[0].qname = Name::from("mx.example.com");
+        query.questions[0].content = [0x00, 0x0F, 0x00, 0x01];
+        let exchange = RData::MX { preference: 10, exchange: Name::from("mail.example.com") };
+        let answer = ResourceRecord::new(Name::from("mx.example.com"), TYPE::MX, CLASS::IN, 60, exchange);
+        let reply = question_reply(&query, RCODE::NoError, vec![answer], false).datagram;
+        Cache::insert(&Cache::Key::from_query(&query), &reply);
+
+        // Served from the cache, as no upstream server is set in tests
+        let address: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let reply = resolve_question(query, address).await;
+        assert!(reply.datagram.header.get_rcode() == RCODE::NoError);
+        assert_eq!(reply.datagram.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_failure() {
+        // No upstream server is set in tests, so every forwarded query fails.
+        let address: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        for content in [[0x00, 0x0F, 0x00, 0x01], [0x00, 0x01, 0x00, 0x01]] {
+            let mut query = Datagram::unserialize(&QUERY).unwrap();
+            query.questions[0].qname = Name::from("failure.example.com");
+            query.questions[0].content = content;
+            let reply = resolve_question(query, address).await;
+            assert!(reply.datagram.header.get_rcode() == RCODE::ServFail);
+            assert_eq!(reply.datagram.header.get_id(), 0x1234);
+            assert!(!reply.blocked);
+        }
+    }
+
+    #[test]
+    fn test_reply_opt() {
+        let query = Datagram::unserialize(&QUERY).unwrap();
+        let mut reply = empty_answer(&query);
+        reply.set_opt(Some(Opt::new(4096)));
+        set_reply_opt(&query, &mut reply);
+        assert!(reply.get_opt().is_none());
+
+        let mut edns_query = query.clone();
+        let mut opt = Opt::new(4096);
+        opt.set_dnssec_ok(true);
+        edns_query.set_opt(Some(opt));
+        let mut reply = empty_answer(&query);
+        set_reply_opt(&edns_query, &mut reply);
+        let reply_opt = reply.get_opt().unwrap();
+        assert!(reply_opt.is_dnssec_ok());
+        assert_eq!(reply_opt.udp_payload_size, cli::Args::get_params().get_edns_payload_size());
+    }
+
+    #[test]
+    fn test_format_error() {
+        let address: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let malformed = &QUERY[..20];
+        let error = parse_query(malformed).err().unwrap();
+        assert_eq!(error, ParseError::Truncated);
+
+        let reply = format_error_answer(malformed, &error, &address).unwrap();
+        assert_eq!(reply.header.get_id(), 0x1234);
+        assert!(!reply.header.is_question());
+        assert_eq!(reply.header.get_rcode().to_string(), RCODE::FormError.to_string());
+        assert_eq!(reply.serialize().len(), Header::LENGTH as usize);
+
+        assert!(format_error_answer(&QUERY[..4], &error, &address).is_none());
+    }
+
+    fn question_reply(query: &Datagram, rcode: RCODE, answers: Vec<ResourceRecord>, blocked: bool) -> QuestionReply {
+        let mut datagram = empty_answer(query);
+        datagram.header.set_rcode(rcode);
+        datagram.answers = answers;
+        datagram.update_counts();
+        QuestionReply { datagram, blocked }
+    }
+
+    fn multi_question_query() -> Datagram {
+        let mut query = Datagram::unserialize(&QUERY).unwrap();
+        let mut question = query.questions[0].clone();
+        question.content = [0x00, 0x1C, 0x00, 0x01];
+        query.questions.push(question);
+        query.set_opt(Some(Opt::new(1232)));
+        query.update_counts();
+        query
+    }
+
+    fn answer_record() -> ResourceRecord {
+        ResourceRecord::unserialize(
+            &[0, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x04, 93, 184, 216, 34],
+            0,
+        ).unwrap().0
+    }
+
+    #[test]
+    fn test_split_questions() {
+        let query = multi_question_query();
+        let queries = split_questions(&query);
+        assert_eq!(queries.len(), 2);
+        for (split, question) in queries.iter().zip(query.questions.iter()) {
+            assert_eq!(split.header.question_count(), 1);
+            assert!(split.questions[0] == *question);
+            assert!(split.get_opt().is_some());
+            assert_eq!(split.header.get_id(), 0x1234);
+        }
+    }
+
+    #[test]
+    fn test_merge_replies() {
+        let query = multi_question_query();
+        let queries = split_questions(&query);
+        let replies = vec![
+            question_reply(&queries[0], RCODE::NoError, vec![answer_record()], false),
+            question_reply(&queries[1], RCODE::ServFail, vec![], false),
+        ];
+        let merged = merge_replies(&query, replies, &BlockPolicy::Message);
+        assert_eq!(merged.header.question_count(), 2);
+        assert_eq!(merged.header.answer_count(), 1);
+        assert!(merged.header.get_rcode() == RCODE::ServFail);
+        // Second question compressed to a pointer, answer for the root name
+        assert_eq!(merged.serialize().len(), QUERY.len() + 6 + 15);
+    }
+
+    #[test]
+    fn test_merge_blocked_replies() {
+        let query = multi_question_query();
+        let queries = split_questions(&query);
+        let replies = || vec![
+            question_reply(&queries[0], RCODE::NoError, vec![answer_record()], false),
+            question_reply(&queries[1], RCODE::NXDomain, vec![], true),
+        ];
+
+        let merged = merge_replies(&query, replies(), &BlockPolicy::Message);
+        assert!(merged.header.get_rcode() == RCODE::NXDomain);
+        assert_eq!(merged.header.answer_count(), 0);
+        assert_eq!(merged.header.question_count(), 2);
+
+        let merged = merge_replies(&query, replies(), &BlockPolicy::Question);
+        assert!(merged.header.get_rcode() == RCODE::NoError);
+        assert_eq!(merged.header.answer_count(), 1);
+
+        let all_blocked = vec![
+            question_reply(&queries[0], RCODE::NXDomain, vec![], true),
+            question_reply(&queries[1], RCODE::NXDomain, vec![], true),
+        ];
+        let merged = merge_replies(&query, all_blocked, &BlockPolicy::Question);
+        assert!(merged.header.get_rcode() == RCODE::NXDomain);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut reply = Datagram::unserialize(&QUERY).unwrap();
+        let (answer, _) = ResourceRecord::unserialize(
+            &[0, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x04, 93, 184, 216, 34],
+            0,
+        ).unwrap();
+        reply.answers.push(answer);
+        reply.header.set_answer_count(1);
+        reply.set_opt(Some(Opt::new(512)));
+
+        let truncated = truncate(&reply);
+        assert!(truncated.header.is_truncated());
+        assert_eq!(truncated.header.answer_count(), 0);
+        assert_eq!(truncated.questions.len(), 1);
+        assert!(truncated.get_opt().is_some());
+    }
+}
@@ -0,0 +1,420 @@
+use crate::dns::dto::datagram::Datagram;
+use crate::dns::tcp;
+use super::error::UpstreamError;
+use super::pool::PendingQueries;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Port of DNS-over-TLS servers (RFC 7858).
+pub const DOT_PORT: u16 = 853;
+
+/// Configuration of TLS clients, trusting the usual web authorities.
+///
+/// Certificates must also match one of `pins`, the SHA-256 digests of
+/// their public key (SPKI), if some are given.
+pub fn client_config(pins: Vec<Vec<u8>>) -> ClientConfig {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    client_config_with_roots(roots, pins)
+}
+
+pub fn client_config_with_roots(roots: RootCertStore, pins: Vec<Vec<u8>>) -> ClientConfig {
+    let verifier = WebPkiServerVerifier::builder(Arc::new(roots))
+        .build()
+        .expect("Unable to build the TLS certificate verifier");
+    ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { verifier, pins }))
+        .with_no_client_auth()
+}
+
+/// # Pinned certificate verifier
+///
+/// Verifies certificates against the trusted authorities and the server
+/// name, then against the SPKI pins (RFC 7858, section 4.2), if any.
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self
+            .verifier
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        if self.pins.is_empty() {
+            return Ok(verified);
+        }
+        let (_, certificate) = x509_parser::parse_x509_certificate(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let digest = Sha256::digest(certificate.public_key().raw);
+        if self.pins.iter().any(|pin| pin.as_slice() == digest.as_slice()) {
+            Ok(verified)
+        } else {
+            log::error!("The public key of {:?} does not match any pin", server_name);
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// # DNS-over-TLS upstream (RFC 7858)
+///
+/// A single connection to the server, opened on first use and shared by
+/// every query. Queries are pipelined: they are sent without waiting for
+/// the previous replies, which are matched by ID as they arrive.
+///
+/// ## Reconnection
+///
+/// Servers close idle connections. Queries pending on a closed connection,
+/// about to be sent on it, or failing to be written to it before it is
+/// known to be closed, are sent once more on a new connection.
+pub struct TlsUpstream {
+    address: SocketAddr,
+
+    /// Name given after the address, or the address itself, which the
+    /// certificate of the server must be valid for and is sent with SNI.
+    name: ServerName<'static>,
+    connector: TlsConnector,
+    connection: Mutex<Option<Arc<Connection>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl TlsUpstream {
+    pub fn new(address: SocketAddr, name: ServerName<'static>, config: Arc<ClientConfig>) -> Self {
+        Self {
+            address,
+            name,
+            connector: TlsConnector::from(config),
+            connection: Mutex::new(None),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sends the query again on a new connection if the connection it was
+    /// sent on is closed without a reply, or fails to send it, as servers
+    /// close idle connections.
+    pub async fn query(&self, datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+        let reply = match self.query_once(datagram).await {
+            Err(UpstreamError::Closed(_) | UpstreamError::Send(..)) => {
+                log::debug!("Sending the query again on a new connection to {}", self.address);
+                self.query_once(datagram).await?
+            }
+            result => result?,
+        };
+
+        let mut reply = Datagram::unserialize(&reply).map_err(|e| UpstreamError::Malformed(self.address, e))?;
+        // Queries pipelined on the connection get IDs unique to it.
+        reply.header.set_id(datagram.header.get_id());
+        Ok(reply)
+    }
+
+    async fn query_once(&self, datagram: &Datagram) -> Result<Vec<u8>, UpstreamError> {
+        let connection = self.connect().await?;
+        let connection = &*connection;
+        let address = self.address;
+        connection
+            .pending
+            .query(&datagram.serialize(), &datagram.questions[0], address, move |query| async move {
+                let mut writer = connection.writer.lock().await;
+                let result = tcp::write_message(&mut *writer, &query).await;
+                result.map_err(|e| {
+                    connection.pending.close();
+                    UpstreamError::Send(address, e)
+                })
+            })
+            .await
+    }
+
+    /// Returns the connection shared by queries, or a new one if the
+    /// server closed it.
+    async fn connect(&self) -> Result<Arc<Connection>, UpstreamError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(open) = connection.as_ref().filter(|open| !open.pending.is_closed()) {
+            return Ok(Arc::clone(open));
+        }
+
+        let stream = TcpStream::connect(self.address)
+            .await
+            .map_err(|e| UpstreamError::Connect(self.address, e))?;
+        let stream = self
+            .connector
+            .connect(self.name.clone(), stream)
+            .await
+            .map_err(|e| UpstreamError::Tls(self.address, e))?;
+        log::debug!("Opened a TLS connection to {}", self.address);
+
+        let (reader, writer) = tokio::io::split(stream);
+        let open = Arc::new(Connection {
+            writer: Mutex::new(writer),
+            pending: PendingQueries::new(Arc::clone(&self.dropped)),
+        });
+        tokio::spawn(Arc::clone(&open).receive(reader, self.address));
+        *connection = Some(Arc::clone(&open));
+        Ok(open)
+    }
+}
+
+struct Connection {
+    writer: Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    pending: PendingQueries,
+}
+
+impl Connection {
+    async fn receive(self: Arc<Self>, mut reader: ReadHalf<TlsStream<TcpStream>>, address: SocketAddr) {
+        loop {
+            match tcp::read_message(&mut reader).await {
+                Ok(Some(reply)) => self.pending.dispatch(&reply, address),
+                Ok(None) => {
+                    log::debug!("{} closed the TLS connection", address);
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("Couldn't receive message from {} over TLS: {}", address, e);
+                    break;
+                }
+            }
+        }
+        self.pending.close();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dns::server::fixtures::query;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::ServerConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Certificate for `name` signed by a new authority.
+    pub struct TestCertificate {
+        pub roots: RootCertStore,
+        pub chain: Vec<CertificateDer<'static>>,
+        pub key: PrivateKeyDer<'static>,
+
+        /// SHA-256 digest of the public key of the certificate.
+        pub pin: Vec<u8>,
+    }
+
+    pub fn certificate(name: &str) -> TestCertificate {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let authority = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &authority)
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(authority.der().clone()).unwrap();
+        let (_, parsed) = x509_parser::parse_x509_certificate(certificate.der()).unwrap();
+        TestCertificate {
+            roots,
+            pin: Sha256::digest(parsed.public_key().raw).to_vec(),
+            chain: vec![certificate.der().clone()],
+            key: PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+        }
+    }
+
+    /// DNS-over-TLS server answering queries by pairs, in the reverse order,
+    /// then closing the connection. Returns its address and the number of
+    /// connections accepted.
+    async fn tls_server(certificate: &TestCertificate) -> (SocketAddr, Arc<AtomicUsize>) {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificate.chain.clone(), certificate.key.clone_key())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&connections);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::Relaxed);
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut queries = Vec::new();
+                for _ in 0..2 {
+                    queries.push(tcp::read_message(&mut stream).await.unwrap().unwrap());
+                }
+                for mut reply in queries.into_iter().rev() {
+                    reply[2] |= 0x80;
+                    tcp::write_message(&mut stream, &reply).await.unwrap();
+                }
+            }
+        });
+        (address, connections)
+    }
+
+    fn upstream(address: SocketAddr, name: &str, certificate: &TestCertificate, pins: Vec<Vec<u8>>) -> TlsUpstream {
+        let config = client_config_with_roots(certificate.roots.clone(), pins);
+        TlsUpstream::new(address, ServerName::try_from(name.to_string()).unwrap(), Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_queries() {
+        let certificate = certificate("dns.test");
+        let (address, connections) = tls_server(&certificate).await;
+        let upstream = upstream(address, "dns.test", &certificate, vec![]);
+
+        let (first, second) = (query(1), query(2));
+        let (first_reply, second_reply) = tokio::join!(upstream.query(&first), upstream.query(&second));
+        assert_eq!(first_reply.unwrap().header.get_id(), 1);
+        assert_eq!(second_reply.unwrap().header.get_id(), 2);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        // The server closed the connection, a new one is opened
+        let (first, second) = (query(3), query(4));
+        let (first_reply, second_reply) = tokio::join!(upstream.query(&first), upstream.query(&second));
+        assert_eq!(first_reply.unwrap().header.get_id(), 3);
+        assert_eq!(second_reply.unwrap().header.get_id(), 4);
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection() {
+        // Closes the first connection right after the handshake, like an
+        // idle connection, and answers on the next one
+        let certificate = certificate("dns.test");
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificate.chain.clone(), certificate.key.clone_key())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(acceptor.accept(stream).await.unwrap());
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut reply = tcp::read_message(&mut stream).await.unwrap().unwrap();
+            reply[2] |= 0x80;
+            tcp::write_message(&mut stream, &reply).await.unwrap();
+        });
+
+        let upstream = upstream(address, "dns.test", &certificate, vec![]);
+        let reply = tokio::time::timeout(Duration::from_secs(1), upstream.query(&query(1))).await;
+        assert_eq!(reply.unwrap().unwrap().header.get_id(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reset_connection() {
+        // Answers on the first connection, then resets it while it is idle,
+        // and answers on the next one. The server runs on its own runtime
+        // so that the test can block its runtime while the reset arrives.
+        let certificate = certificate("dns.test");
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificate.chain.clone(), certificate.key.clone_key())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (reset_sender, reset) = std::sync::mpsc::channel();
+        let (idle_sender, idle) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async move {
+                listener.set_nonblocking(true).unwrap();
+                let listener = TcpListener::from_std(listener).unwrap();
+                let mut reset_sender = Some(reset_sender);
+                for _ in 0..2 {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut stream = acceptor.accept(stream).await.unwrap();
+                    let mut reply = tcp::read_message(&mut stream).await.unwrap().unwrap();
+                    reply[2] |= 0x80;
+                    tcp::write_message(&mut stream, &reply).await.unwrap();
+                    if let Some(reset_sender) = reset_sender.take() {
+                        idle.recv().unwrap();
+                        stream.get_ref().0.set_zero_linger().unwrap();
+                        drop(stream);
+                        reset_sender.send(()).unwrap();
+                    }
+                }
+            });
+        });
+
+        let upstream = upstream(address, "dns.test", &certificate, vec![]);
+        assert_eq!(upstream.query(&query(1)).await.unwrap().header.get_id(), 1);
+        // Blocks until the idle connection is reset, so that the next query
+        // is written before the connection is known to be closed
+        idle_sender.send(()).unwrap();
+        reset.recv().unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(1), upstream.query(&query(2))).await;
+        assert_eq!(reply.unwrap().unwrap().header.get_id(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_name() {
+        let certificate = certificate("dns.test");
+        let (address, _) = tls_server(&certificate).await;
+        let upstream = upstream(address, "other.test", &certificate, vec![]);
+
+        assert!(matches!(upstream.query(&query(1)).await, Err(UpstreamError::Tls(..))));
+    }
+
+    #[tokio::test]
+    async fn test_pins() {
+        let certificate = certificate("dns.test");
+        let (address, _) = tls_server(&certificate).await;
+
+        let pinned = upstream(address, "dns.test", &certificate, vec![vec![0; 32], certificate.pin.clone()]);
+        let (first, second) = (query(1), query(2));
+        let (first_reply, second_reply) = tokio::join!(pinned.query(&first), pinned.query(&second));
+        assert!(first_reply.is_ok() && second_reply.is_ok());
+
+        let mismatched = upstream(address, "dns.test", &certificate, vec![vec![0; 32]]);
+        assert!(matches!(mismatched.query(&query(1)).await, Err(UpstreamError::Tls(..))));
+    }
+}
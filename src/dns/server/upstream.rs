@@ -0,0 +1,644 @@
+use crate::cli::{self, UpstreamAddress, UpstreamStrategy};
+use crate::dns::tcp;
+use super::error::UpstreamError;
+use super::https::HttpsUpstream;
+use super::pool::{validate_reply, SocketPool};
+use super::tls::{self, TlsUpstream};
+use crate::dns::dto::{
+    datagram::Datagram,
+    header::Header,
+    name::Name,
+    opt::Opt,
+    question::Question,
+};
+use rustls::pki_types::ServerName;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Set at startup, once the arguments are validated.
+static UPSTREAMS: OnceLock<Upstreams> = OnceLock::new();
+
+/// Consecutive failures after which an upstream server is considered down.
+const MAX_FAILURES: u32 = 3;
+
+/// Interval between health checks of upstream servers that are down.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sets the upstream servers queries are forwarded to.
+pub fn init(upstreams: Upstreams) {
+    if UPSTREAMS.set(upstreams).is_err() {
+        log::warn!("Upstream servers are already set");
+    }
+}
+
+/// Forwards a query to the upstream servers, according to the strategy
+/// given in the arguments.
+pub async fn forward(datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+    UPSTREAMS.get().ok_or(UpstreamError::NoUpstream)?.forward(datagram).await
+}
+
+/// Waits at most `deadline` for the reply to a query forwarded on its own
+/// task, which goes on in the background after it, so that the client
+/// gets an answer before it gives up (RFC 8767, section 5).
+pub async fn wait_for(forward: JoinHandle<Result<Datagram, UpstreamError>>, deadline: Duration) -> Result<Datagram, UpstreamError> {
+    match tokio::time::timeout(deadline, forward).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(_) => Err(UpstreamError::Cancelled),
+        },
+        Err(_) => Err(UpstreamError::Deadline),
+    }
+}
+
+/// Probes upstream servers that are down every `HEALTH_CHECK_INTERVAL`,
+/// so that they are used again once they recover.
+pub async fn check_health() {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(upstreams) = UPSTREAMS.get() {
+            upstreams.check_health().await;
+        }
+    }
+}
+
+/// # Upstream server
+///
+/// Resolver to which queries are forwarded, with the statistics used to
+/// choose between servers.
+///
+/// ## Health
+///
+/// A server is down after `MAX_FAILURES` consecutive queries without a
+/// reply, and up again after any reply, including to health checks.
+pub struct Upstream {
+    pub address: SocketAddr,
+    transport: Transport,
+
+    /// Smoothed round trip time in microseconds, 0 until the first reply.
+    latency: AtomicU64,
+
+    /// Consecutive queries without a reply.
+    failures: AtomicU32,
+}
+
+impl Upstream {
+    pub fn new(address: SocketAddr, transport: Transport) -> Self {
+        Self {
+            address,
+            transport,
+            latency: AtomicU64::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) < MAX_FAILURES
+    }
+
+    pub fn get_latency(&self) -> Duration {
+        Duration::from_micros(self.latency.load(Ordering::Relaxed))
+    }
+
+    /// Updates the latency with a weight of 1/8 for the new sample, like the
+    /// smoothed round trip time of TCP (RFC 6298).
+    fn record_success(&self, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        let latency = match self.latency.load(Ordering::Relaxed) {
+            0 => sample,
+            latency => (latency * 7 + sample) / 8,
+        };
+        self.latency.store(latency, Ordering::Relaxed);
+        if self.failures.swap(0, Ordering::Relaxed) >= MAX_FAILURES {
+            log::info!("Upstream server {} is up again", self.address);
+        }
+    }
+
+    fn record_failure(&self) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 == MAX_FAILURES {
+            log::warn!("Upstream server {} is down", self.address);
+        }
+    }
+
+    /// Sends a query and waits at most `timeout` for the reply, recording
+    /// the outcome.
+    async fn query(&self, pool: &SocketPool, datagram: &Datagram, timeout: Duration) -> Result<Datagram, UpstreamError> {
+        let start = Instant::now();
+        let query = async {
+            match &self.transport {
+                Transport::Udp => query_upstream(pool, datagram, self.address).await,
+                Transport::Tls(upstream) => upstream.query(datagram).await,
+                Transport::Https(upstream) => upstream.query(datagram).await,
+            }
+        };
+        let result = tokio::time::timeout(timeout, query)
+            .await
+            .unwrap_or(Err(UpstreamError::Timeout(self.address)));
+        match &result {
+            Ok(_) => self.record_success(start.elapsed()),
+            Err(e) => {
+                log::warn!("{}", e);
+                self.record_failure();
+            }
+        }
+        result
+    }
+}
+
+/// Protocol used to reach an upstream server.
+pub enum Transport {
+    /// Plain DNS over UDP, and TCP for truncated replies.
+    Udp,
+
+    /// DNS-over-TLS.
+    Tls(TlsUpstream),
+
+    /// DNS-over-HTTPS.
+    Https(HttpsUpstream),
+}
+
+/// # Upstream servers
+///
+/// Servers to which queries are forwarded, tried one after the other until
+/// one replies. Servers that are down are only tried as a last resort.
+///
+/// ## Retries
+///
+/// When no server replies, every server is tried again, up to `retries`
+/// times, waiting `backoff` before the first retry and twice as long before
+/// each of the next ones.
+pub struct Upstreams {
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: UpstreamStrategy,
+    pool: Arc<SocketPool>,
+
+    /// Time to wait for the reply of a server, for each attempt.
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+
+    /// Server to start from with the round-robin strategy.
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    pub fn new(upstreams: Vec<Upstream>, strategy: UpstreamStrategy, timeout: Duration, retries: u32, backoff: Duration) -> Self {
+        Self {
+            upstreams: upstreams.into_iter().map(Arc::new).collect(),
+            strategy,
+            pool: Arc::new(SocketPool::default()),
+            timeout,
+            retries,
+            backoff,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reads the upstream servers from the arguments, or the first invalid
+    /// one.
+    pub async fn from_args(args: &cli::Args) -> Result<Self, String> {
+        let upstreams = args
+            .get_upstreams()
+            .await?
+            .into_iter()
+            .map(|upstream| match upstream {
+                UpstreamAddress::Udp(address) => Ok(Upstream::new(address, Transport::Udp)),
+                // Each server gets its own configuration, as pins only apply to it.
+                UpstreamAddress::Tls { address, name, pins } => {
+                    let name = parse_server_name(&name)?;
+                    let config = Arc::new(tls::client_config(pins));
+                    Ok(Upstream::new(address, Transport::Tls(TlsUpstream::new(address, name, config))))
+                }
+                UpstreamAddress::Https { address, url, pins } => {
+                    let host = url.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+                    let name = parse_server_name(host)?;
+                    let mut config = tls::client_config(pins);
+                    config.alpn_protocols = vec![b"h2".to_vec()];
+                    let transport = Transport::Https(HttpsUpstream::new(address, url, name, Arc::new(config)));
+                    Ok(Upstream::new(address, transport))
+                }
+            })
+            .collect::<Result<Vec<Upstream>, String>>()?;
+        Ok(Self::new(
+            upstreams,
+            args.upstream_strategy.clone(),
+            args.get_upstream_timeout(),
+            args.upstream_retries,
+            args.get_upstream_backoff(),
+        ))
+    }
+
+    /// Servers in the order they should be tried: servers that are up,
+    /// according to the strategy, then the others from the least failing.
+    fn get_order(&self) -> Vec<Arc<Upstream>> {
+        let (mut healthy, mut unhealthy): (Vec<Arc<Upstream>>, Vec<Arc<Upstream>>) =
+            self.upstreams.iter().cloned().partition(|upstream| upstream.is_healthy());
+        match self.strategy {
+            UpstreamStrategy::Ordered | UpstreamStrategy::Race => {}
+            UpstreamStrategy::RoundRobin => {
+                if !healthy.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                    healthy.rotate_left(start);
+                }
+            }
+            // Servers that never replied come first, so that they get measured.
+            UpstreamStrategy::Fastest => healthy.sort_by_key(|upstream| upstream.get_latency()),
+        }
+        unhealthy.sort_by_key(|upstream| upstream.failures.load(Ordering::Relaxed));
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// Returns the first reply, or the error of the last attempt.
+    pub async fn forward(&self, datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match self.try_all(datagram).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => e,
+            };
+            if attempt == self.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            log::debug!("Retrying {} in {} ms", datagram.questions[0].qname, backoff.as_millis());
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    /// Tries every server once, in order.
+    async fn try_all(&self, datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+        let mut order = self.get_order();
+        let mut error = UpstreamError::NoUpstream;
+        if self.strategy == UpstreamStrategy::Race && !order.is_empty() {
+            let count = order.iter().filter(|upstream| upstream.is_healthy()).count().max(1);
+            let racers: Vec<Arc<Upstream>> = order.drain(..count).collect();
+            match self.race(racers, datagram).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => error = e,
+            }
+        }
+
+        for upstream in order {
+            match upstream.query(&self.pool, datagram, self.timeout).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => error = e,
+            }
+            log::debug!("Failing over from {}", upstream.address);
+        }
+        Err(error)
+    }
+
+    /// Sends the query to every server at once, keeping the first reply.
+    async fn race(&self, upstreams: Vec<Arc<Upstream>>, datagram: &Datagram) -> Result<Datagram, UpstreamError> {
+        let mut queries = JoinSet::new();
+        for upstream in upstreams {
+            let datagram = datagram.clone();
+            let pool = Arc::clone(&self.pool);
+            let timeout = self.timeout;
+            queries.spawn(async move { upstream.query(&pool, &datagram, timeout).await });
+        }
+        let mut error = UpstreamError::NoUpstream;
+        // Queries still running are aborted when the set is dropped.
+        while let Some(result) = queries.join_next().await {
+            match result {
+                Ok(Ok(reply)) => return Ok(reply),
+                Ok(Err(e)) => error = e,
+                Err(e) => log::error!("Upstream query failed: {}", e),
+            }
+        }
+        Err(error)
+    }
+
+    async fn check_health(&self) {
+        for upstream in self.upstreams.iter().filter(|upstream| !upstream.is_healthy()) {
+            log::debug!("Checking the health of {}", upstream.address);
+            // The outcome is recorded by the query itself.
+            let _ = upstream.query(&self.pool, &get_health_query(), self.timeout).await;
+        }
+    }
+}
+
+fn parse_server_name(name: &str) -> Result<ServerName<'static>, String> {
+    ServerName::try_from(name.to_string()).map_err(|_| format!("invalid TLS server name {}", name))
+}
+
+/// Query for the name servers of the root zone, which every resolver can
+/// answer.
+fn get_health_query() -> Datagram {
+    let question = Question {
+        qname: Name::root(),
+        content: [0x00, 0x02, 0x00, 0x01],
+    };
+    Datagram {
+        header: Header::new_question(),
+        questions: vec![question],
+        answers: vec![],
+        authorities: vec![],
+        additionals: vec![],
+    }
+}
+
+async fn query_upstream(pool: &SocketPool, datagram: &Datagram, upstream_addr: SocketAddr) -> Result<Datagram, UpstreamError> {
+    let payload_size = cli::Args::get_params().get_edns_payload_size();
+    let query = get_upstream_query(datagram, payload_size);
+    let send_time = Instant::now();
+    log::debug!("Forwarding request to {}", upstream_addr);
+    let buf = pool.query(&query.serialize(), &query.questions[0], upstream_addr).await?;
+
+    let is_truncated = Header::unserialize(&buf).is_ok_and(|header| header.is_truncated());
+    let mut reply = if is_truncated {
+        // The truncated reply may end in the middle of a record,
+        // so it is not parsed and the whole query is sent again over TCP.
+        log::debug!("Reply from {} is truncated, retrying over TCP", upstream_addr);
+        get_tcp_forwarded_answer(&query, upstream_addr).await?
+    } else {
+        Datagram::unserialize(&buf).map_err(|e| UpstreamError::Malformed(upstream_addr, e))?
+    };
+    // The pool sent the query with a random ID, the client expects its own.
+    reply.header.set_id(datagram.header.get_id());
+    let receiving_delay = send_time.elapsed().as_millis();
+
+    log::debug!(
+        "Received reply from {} in {} ms\n{}",
+        upstream_addr,
+        receiving_delay,
+        reply
+    );
+    Ok(reply)
+}
+
+/// Advertises this server's payload size to upstream, keeping the client's
+/// other EDNS parameters if it sent some.
+fn get_upstream_query(datagram: &Datagram, payload_size: u16) -> Datagram {
+    let mut query = datagram.clone();
+    let mut opt = datagram.get_opt().unwrap_or_else(|| Opt::new(0));
+    opt.udp_payload_size = payload_size;
+    query.set_opt(Some(opt));
+    query
+}
+
+async fn get_tcp_forwarded_answer(datagram: &Datagram, upstream_addr: SocketAddr) -> Result<Datagram, UpstreamError> {
+    let mut stream = TcpStream::connect(upstream_addr)
+        .await
+        .map_err(|e| UpstreamError::Connect(upstream_addr, e))?;
+    tcp::write_message(&mut stream, &datagram.serialize())
+        .await
+        .map_err(|e| UpstreamError::Send(upstream_addr, e))?;
+
+    let message = tcp::read_message(&mut stream)
+        .await
+        .map_err(|e| UpstreamError::Receive(upstream_addr, e))?
+        .ok_or(UpstreamError::Closed(upstream_addr))?;
+    let reply = Datagram::unserialize(&message).map_err(|e| UpstreamError::Malformed(upstream_addr, e))?;
+    validate_reply(&reply.header, reply.questions.first(), datagram.header.get_id(), &datagram.questions[0])
+        .map_err(|reason| UpstreamError::Mismatched(upstream_addr, reason))?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::server::fixtures::QUERY;
+    use base64::Engine;
+    use clap::Parser;
+    use tokio::net::{TcpListener, UdpSocket};
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn new_upstreams(addresses: Vec<SocketAddr>, strategy: UpstreamStrategy) -> Upstreams {
+        Upstreams::new(udp_upstreams(addresses), strategy, TIMEOUT, 0, Duration::ZERO)
+    }
+
+    fn udp_upstreams(addresses: Vec<SocketAddr>) -> Vec<Upstream> {
+        addresses.into_iter().map(|address| Upstream::new(address, Transport::Udp)).collect()
+    }
+
+    /// Upstream server replying to every query after `delay`, or never.
+    async fn fake_upstream(delay: Option<Duration>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (length, client) = socket.recv_from(&mut buf).await.unwrap();
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                    let mut reply = buf[..length].to_vec();
+                    // QR bit
+                    reply[2] |= 0x80;
+                    socket.send_to(&reply, client).await.unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_truncated_reply_retries_over_tcp() {
+        let udp_upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = udp_upstream.local_addr().unwrap();
+        let tcp_upstream = TcpListener::bind(upstream_addr).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (length, client) = udp_upstream.recv_from(&mut buf).await.unwrap();
+            let mut truncated = buf[..length].to_vec();
+            // QR and TC bits
+            truncated[2] |= 0x82;
+            udp_upstream.send_to(&truncated, client).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp_upstream.accept().await.unwrap();
+            let mut reply = tcp::read_message(&mut stream).await.unwrap().unwrap();
+            // Drops the OPT record added to the query.
+            reply.truncate(QUERY.len());
+            reply[11] = 0;
+            reply[2] |= 0x80;
+            // ANCOUNT
+            reply[7] = 1;
+            reply.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x04, 93, 184, 216, 34]);
+            tcp::write_message(&mut stream, &reply).await.unwrap();
+        });
+
+        let query = Datagram::unserialize(&QUERY).unwrap();
+        let reply = query_upstream(&SocketPool::default(), &query, upstream_addr).await.unwrap();
+
+        assert!(!reply.header.is_truncated());
+        assert_eq!(reply.header.get_id(), 0x1234);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].get_rdata(), [93, 184, 216, 34]);
+    }
+
+    #[test]
+    fn test_upstream_query_opt() {
+        let query = Datagram::unserialize(&QUERY).unwrap();
+        let upstream_query = get_upstream_query(&query, 1232);
+        assert_eq!(upstream_query.get_opt().unwrap().udp_payload_size, 1232);
+        assert_eq!(upstream_query.header.additional_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let silent = fake_upstream(None).await;
+        let working = fake_upstream(Some(Duration::ZERO)).await;
+        let upstreams = new_upstreams(vec![silent, working], UpstreamStrategy::Ordered);
+        let query = Datagram::unserialize(&QUERY).unwrap();
+
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(upstreams.forward(&query).await.unwrap().header.get_id(), 0x1234);
+        }
+        assert!(!upstreams.upstreams[0].is_healthy());
+        assert!(upstreams.upstreams[1].get_latency() > Duration::ZERO);
+
+        // Servers that are down are tried last
+        assert_eq!(upstreams.get_order()[0].address, working);
+    }
+
+    #[tokio::test]
+    async fn test_all_down() {
+        let silent = fake_upstream(None).await;
+        let upstreams = Upstreams::new(udp_upstreams(vec![silent]), UpstreamStrategy::Ordered, TIMEOUT, 1, Duration::from_millis(10));
+        let query = Datagram::unserialize(&QUERY).unwrap();
+
+        let result = upstreams.forward(&query).await;
+        assert!(matches!(result, Err(UpstreamError::Timeout(address)) if address == silent));
+        // The first attempt and one retry
+        assert_eq!(upstreams.upstreams[0].failures.load(Ordering::Relaxed), 2);
+
+        let upstreams = new_upstreams(vec![], UpstreamStrategy::Race);
+        assert!(matches!(upstreams.forward(&query).await, Err(UpstreamError::NoUpstream)));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        // Drops the first query, as if it was lost
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let flaky = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            socket.recv_from(&mut buf).await.unwrap();
+            let (length, client) = socket.recv_from(&mut buf).await.unwrap();
+            let mut reply = buf[..length].to_vec();
+            reply[2] |= 0x80;
+            socket.send_to(&reply, client).await.unwrap();
+        });
+        let upstreams = Upstreams::new(udp_upstreams(vec![flaky]), UpstreamStrategy::Ordered, TIMEOUT, 1, Duration::from_millis(10));
+        let query = Datagram::unserialize(&QUERY).unwrap();
+
+        assert_eq!(upstreams.forward(&query).await.unwrap().header.get_id(), 0x1234);
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        let slow = fake_upstream(Some(Duration::from_millis(150))).await;
+        let fast = fake_upstream(Some(Duration::ZERO)).await;
+        let upstreams = new_upstreams(vec![slow, fast], UpstreamStrategy::Race);
+        let query = Datagram::unserialize(&QUERY).unwrap();
+
+        let start = Instant::now();
+        assert!(upstreams.forward(&query).await.is_ok());
+        assert!(start.elapsed() < Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_client_timeout() {
+        // With the default arguments, the client gets an answer before it
+        // gives up, even if no server replies.
+        let silent = fake_upstream(None).await;
+        let args = cli::Args::parse_from(["no-dns", "-u", &silent.to_string()]);
+        let upstreams = Arc::new(Upstreams::from_args(&args).await.unwrap());
+        let query = Datagram::unserialize(&QUERY).unwrap();
+
+        let start = Instant::now();
+        let forward = tokio::spawn({
+            let upstreams = Arc::clone(&upstreams);
+            async move { upstreams.forward(&query).await }
+        });
+        assert!(matches!(wait_for(forward, args.get_client_timeout()).await, Err(UpstreamError::Deadline)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // The query goes on in the background
+        tokio::time::sleep(args.get_upstream_timeout() - start.elapsed() + TIMEOUT).await;
+        assert_eq!(upstreams.upstreams[0].failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_forward() {
+        let forward = tokio::spawn(std::future::pending());
+        forward.abort();
+        assert!(matches!(wait_for(forward, TIMEOUT).await, Err(UpstreamError::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_arguments() {
+        async fn from_args(upstream: &str) -> Result<usize, String> {
+            let args = cli::Args::parse_from(["no-dns", "-u", upstream]);
+            Upstreams::from_args(&args).await.map(|upstreams| upstreams.upstreams.len())
+        }
+        assert_eq!(from_args("127.0.0.1,[::1]:5353,tls://127.0.0.1#dns.test").await, Ok(3));
+        // Hosts are only looked up without an address, which tests avoid
+        assert_eq!(from_args("https://127.0.0.1/dns-query,https://[::1]:8443/dns-query").await, Ok(2));
+        assert_eq!(from_args("https://dns.test/dns-query#127.0.0.1").await, Ok(1));
+
+        for upstream in [
+            "notanip",
+            "127.0.0.1:notaport",
+            "tls://notanip",
+            "tls://127.0.0.1#not a name",
+            "tls://127.0.0.1#dns.test#notapin",
+            "https://dns.test/dns-query#notanip",
+            "https://dns.test/dns-query#127.0.0.1#notapin",
+        ] {
+            assert!(from_args(upstream).await.is_err(), "{} is valid", upstream);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pins() {
+        let pin = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let digest = base64::engine::general_purpose::STANDARD.decode(pin).unwrap();
+        let upstream = format!("tls://127.0.0.1#dns.test#{pin},tls://127.0.0.2,https://dns.test/dns-query#127.0.0.3#{pin}#{pin}");
+        let args = cli::Args::parse_from(["no-dns", "-u", &upstream]);
+        let upstreams = args.get_upstreams().await.unwrap();
+
+        // Pins only apply to the server they follow
+        let address = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 853);
+        assert_eq!(upstreams[0], UpstreamAddress::Tls { address: address("127.0.0.1"), name: String::from("dns.test"), pins: vec![digest.clone()] });
+        assert_eq!(upstreams[1], UpstreamAddress::Tls { address: address("127.0.0.2"), name: String::from("127.0.0.2"), pins: vec![] });
+        assert!(matches!(&upstreams[2], UpstreamAddress::Https { pins, .. } if *pins == vec![digest.clone(), digest]));
+    }
+
+    #[test]
+    fn test_order() {
+        let addresses: Vec<SocketAddr> = ["127.0.0.1:53", "127.0.0.2:53", "127.0.0.3:53"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        let order = |upstreams: &Upstreams| {
+            upstreams.get_order().iter().map(|upstream| upstream.address).collect::<Vec<SocketAddr>>()
+        };
+
+        let upstreams = new_upstreams(addresses.clone(), UpstreamStrategy::RoundRobin);
+        assert_eq!(order(&upstreams), addresses);
+        assert_eq!(order(&upstreams), [addresses[1], addresses[2], addresses[0]]);
+
+        let upstreams = new_upstreams(addresses.clone(), UpstreamStrategy::Fastest);
+        upstreams.upstreams[0].record_success(Duration::from_millis(30));
+        upstreams.upstreams[1].record_success(Duration::from_millis(10));
+        upstreams.upstreams[2].record_success(Duration::from_millis(20));
+        assert_eq!(order(&upstreams), [addresses[1], addresses[2], addresses[0]]);
+
+        for _ in 0..MAX_FAILURES {
+            upstreams.upstreams[1].record_failure();
+        }
+        assert_eq!(order(&upstreams), [addresses[2], addresses[0], addresses[1]]);
+    }
+}
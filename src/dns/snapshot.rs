@@ -0,0 +1,222 @@
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::cache::{self as Cache, Entry, Key};
+use super::compression::LabelTree;
+use super::dto::question::Question;
+
+/// # Cache snapshot
+///
+/// Entries of the cache saved to a file, so that they survive restarts.
+///
+/// ## Structure
+/// MAGIC: "NODNS" followed by the format version (2)
+///
+/// TIME: Seconds since the UNIX epoch when the snapshot was taken (64 bits)
+///
+/// ENTRIES: {length (16 bits), question, flags (8 bits), entry}*
+///
+/// FLAGS: DO (1 bit), CD (1 bit), 0 (6 bits)
+///
+/// Questions and records are in the wire format, compressed within their
+/// entry. Entries are written with their remaining TTLs, which are counted
+/// down again by the time elapsed until they are loaded.
+const MAGIC: &[u8; 6] = b"NODNS\x02";
+
+const DNSSEC_OK: u8 = 0x80;
+const CHECKING_DISABLED: u8 = 0x40;
+
+/// Writes every entry of the cache to `path`, returning the number of entries.
+///
+/// The file is replaced at once, so that a crash while writing does not
+/// leave a partial snapshot behind.
+pub async fn save(path: &Path) -> io::Result<usize> {
+    let entries = Cache::export();
+    let bytes = serialize(&entries, now());
+    let temporary_path = path.with_extension("tmp");
+    tokio::fs::write(&temporary_path, bytes).await?;
+    tokio::fs::rename(&temporary_path, path).await?;
+    Ok(entries.len())
+}
+
+/// Adds the entries saved in `path` to the cache, returning the number of
+/// entries that have not expired since.
+pub async fn load(path: &Path) -> io::Result<usize> {
+    let bytes = tokio::fs::read(path).await?;
+    let entries = unserialize(&bytes, now())?;
+    let count = entries.len();
+    for (key, entry) in entries {
+        Cache::import(&key, entry);
+    }
+    Ok(count)
+}
+
+/// Saves the cache to `path` every `interval`.
+pub async fn save_periodically(path: &Path, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately, when there is nothing to save yet.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match save(path).await {
+            Ok(count) => log::debug!("Saved {} cache entries to {}", count, path.display()),
+            Err(e) => log::error!("Couldn't save the cache to {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn serialize(entries: &[(Key, Entry)], time: u64) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(512);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&time.to_be_bytes());
+
+    for (key, entry) in entries {
+        // Compression pointers are relative to the start of the entry.
+        let mut entry_bytes: Vec<u8> = Vec::with_capacity(128);
+        let mut lt = LabelTree::default();
+        key.question.serialize(&mut entry_bytes, &mut lt);
+        let mut flags = 0;
+        if key.dnssec_ok {
+            flags |= DNSSEC_OK;
+        }
+        if key.checking_disabled {
+            flags |= CHECKING_DISABLED;
+        }
+        entry_bytes.push(flags);
+        entry.serialize(&mut entry_bytes, &mut lt);
+
+        match u16::try_from(entry_bytes.len()) {
+            Ok(length) => {
+                bytes.extend_from_slice(&length.to_be_bytes());
+                bytes.extend_from_slice(&entry_bytes);
+            }
+            Err(_) => log::debug!("Not saving {}, its entry is too large", key.question.qname),
+        }
+    }
+    bytes
+}
+
+/// Reads the entries of a snapshot taken at `time`, leaving out those that
+/// expired by `now`.
+fn unserialize(bytes: &[u8], now: u64) -> io::Result<Vec<(Key, Entry)>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    if bytes.len() < MAGIC.len() + 8 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid(String::from("not a cache snapshot")));
+    }
+    let time = u64::from_be_bytes(bytes[MAGIC.len()..MAGIC.len() + 8].try_into().unwrap());
+    let downtime = u32::try_from(now.saturating_sub(time)).unwrap_or(u32::MAX);
+
+    let mut entries: Vec<(Key, Entry)> = Vec::new();
+    let mut position = MAGIC.len() + 8;
+    while position < bytes.len() {
+        let length = bytes
+            .get(position..position + 2)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .ok_or_else(|| invalid(String::from("truncated entry length")))?;
+        let entry_bytes = bytes
+            .get(position + 2..position + 2 + length)
+            .ok_or_else(|| invalid(String::from("truncated entry")))?;
+        position += 2 + length;
+
+        let (question, offset) = Question::unserialize(entry_bytes, 0).map_err(|e| invalid(e.to_string()))?;
+        let flags = *entry_bytes
+            .get(offset as usize)
+            .ok_or_else(|| invalid(String::from("truncated entry flags")))?;
+        let key = Key {
+            question,
+            dnssec_ok: flags & DNSSEC_OK != 0,
+            checking_disabled: flags & CHECKING_DISABLED != 0,
+        };
+        let (mut entry, _) = Entry::unserialize(entry_bytes, offset + 1).map_err(|e| invalid(e.to_string()))?;
+        if entry.get_ttl() > downtime {
+            entry.age(downtime);
+            entries.push((key, entry));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dto::{
+        datagram::Datagram,
+        enums::{CLASS, TYPE},
+        header::Header,
+        name::Name,
+        rdata::RData,
+        resource_record::ResourceRecord,
+    };
+    use std::net::Ipv4Addr;
+
+    fn entry(name: &str, ttl: u32) -> (Key, Entry) {
+        let question = Question {
+            qname: Name::from(name),
+            content: [0, 1, 0, 1],
+        };
+        let answer = ResourceRecord::new(Name::from(name), TYPE::A, CLASS::IN, ttl, RData::A(Ipv4Addr::new(8, 8, 8, 8)));
+        let mut reply = Datagram {
+            header: Header::new_reply(),
+            questions: vec![question.clone()],
+            answers: vec![answer],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        reply.update_counts();
+        let entry = Entry::from_reply(&reply, &Cache::TtlPolicy::from_args(&crate::cli::Args::get_params())).unwrap();
+        (Key::new(&question, true, false), entry)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let entries = vec![entry("google.com", 300), entry("www.google.com", 60)];
+        let bytes = serialize(&entries, 1000);
+
+        let loaded = unserialize(&bytes, 1000).unwrap();
+        assert_eq!(loaded.len(), 2);
+        for ((key, entry), (loaded_key, loaded_entry)) in entries.iter().zip(loaded.iter()) {
+            assert!(key == loaded_key);
+            assert_eq!(entry.answers, loaded_entry.answers);
+        }
+    }
+
+    #[test]
+    fn test_downtime() {
+        let entries = vec![entry("google.com", 300), entry("www.google.com", 60)];
+        let bytes = serialize(&entries, 1000);
+
+        // Entries are counted down by the downtime, or left out if they expired
+        let loaded = unserialize(&bytes, 1100).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].1.get_ttl(), 200);
+        assert_eq!(loaded[0].1.answers[0].get_ttl(), 200);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(unserialize(b"NOTDNS", 0).is_err());
+
+        let bytes = serialize(&[entry("google.com", 300)], 1000);
+        assert!(unserialize(&bytes[..bytes.len() - 1], 1000).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("no-dns-snapshot-{}.bin", std::process::id()));
+        let (key, entry) = entry("snapshot.example.com", 300);
+        Cache::import(&key, entry);
+
+        assert!(save(&path).await.unwrap() >= 1);
+        assert!(load(&path).await.unwrap() >= 1);
+        assert!(Cache::get(&key).is_some());
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
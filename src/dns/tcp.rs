@@ -0,0 +1,77 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Reads a single DNS message from a stream.
+///
+/// Over TCP, every message is prefixed by its length on two bytes
+/// (RFC 1035, section 4.2.2).
+///
+/// Returns `Ok(None)` if the stream was closed before a new message started.
+pub async fn read_message<R>(stream: &mut R) -> io::Result<Option<Vec<u8>>>
+where R: AsyncRead + Unpin, {
+    let mut length: [u8; 2] = [0; 2];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut message: Vec<u8> = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+/// Writes a single DNS message to a stream, prefixed by its length.
+pub async fn write_message<W>(stream: &mut W, message: &[u8]) -> io::Result<()>
+where W: AsyncWrite + Unpin, {
+    let length: u16 = message.len().try_into().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "DNS message is too long for TCP")
+    })?;
+
+    // Written in a single buffer so that pipelined replies never interleave.
+    let mut framed: Vec<u8> = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&length.to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pipelined_messages() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        write_message(&mut client, &[1, 2, 3]).await.unwrap();
+        write_message(&mut client, &[4, 5]).await.unwrap();
+        drop(client);
+
+        assert_eq!(read_message(&mut server).await.unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_message(&mut server).await.unwrap(), Some(vec![4, 5]));
+        assert_eq!(read_message(&mut server).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_length_prefix() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        write_message(&mut client, &[0xAA; 3]).await.unwrap();
+        drop(client);
+
+        let mut bytes = Vec::new();
+        server.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0, 3, 0xAA, 0xAA, 0xAA]);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_message() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        client.write_all(&[0, 4, 1, 2]).await.unwrap();
+        drop(client);
+
+        assert!(read_message(&mut server).await.is_err());
+    }
+}
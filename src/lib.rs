@@ -0,0 +1,3 @@
+mod blocklist;
+pub mod cli;
+pub mod dns;
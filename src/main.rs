@@ -1,12 +1,55 @@
-mod blocklist;
-mod cli;
-mod dns;
-use dns::server;
+use no_dns::cli;
+use no_dns::dns::{server, snapshot};
+use server::upstream::{self, Upstreams};
 use env_logger;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    log::info!("Starting No DNS on {}", cli::Args::get_params().get_bind());
-    server::dispatcher::start().await;
+    let args = cli::Args::get_params();
+    // Invalid upstream servers are reported before anything is bound.
+    let upstreams = Upstreams::from_args(&args).await.unwrap_or_else(|e| cli::Args::exit_with_error(&e));
+    upstream::init(upstreams);
+    log::info!("Starting No DNS on {}", args.get_bind());
+
+    if let Some(path) = args.cache_file.clone() {
+        match snapshot::load(&path).await {
+            Ok(count) => log::info!("Loaded {} cache entries from {}", count, path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("No cache to load from {}", path.display());
+            }
+            Err(e) => log::warn!("Couldn't load the cache from {}: {}", path.display(), e),
+        }
+        let interval = args.get_cache_save_interval();
+        tokio::spawn(async move { snapshot::save_periodically(&path, interval).await });
+    }
+
+    tokio::select! {
+        _ = server::dispatcher::start() => {}
+        _ = shutdown_signal() => log::info!("Shutting down"),
+    }
+
+    if let Some(path) = args.cache_file {
+        match snapshot::save(&path).await {
+            Ok(count) => log::info!("Saved {} cache entries to {}", count, path.display()),
+            Err(e) => log::error!("Couldn't save the cache to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Waits for an interruption (Ctrl+C), or a termination request on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen to termination signals");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }